@@ -27,20 +27,16 @@ async function fetchPeers() {
    return new Set(await res.json());
 }
 
-const announced = new Set();
 async function announce() {
     const data = {
         id: 'mutiny.example.chat',
         nick: state.nick.value,
     };
-    for (const peer of state.peers.value.difference(announced)) {
-        console.log('announcing to', peer);
-        await fetch("/_api/v1/announcements/outbox", {
-            method: 'POST',
-            body: JSON.stringify({peer, data}),
-        });
-        announced.add(peer);
-    }
+    // Sent to all peers, including ones discovered later
+    await fetch("/_api/v1/announcements/broadcast", {
+        method: 'POST',
+        body: JSON.stringify({data}),
+    });
 }
 
 async function fetchAnnouncements() {
@@ -93,13 +89,8 @@ state.announcements.value = await fetchAnnouncements();
 state.peers.value = await fetchPeers();
 await fetchMessages();
 
-// Announce app when new peers discovered or nick changes
-watch([state.peers], announce);
-watch([state.nick], () => {
-    // Re-announce to all peers on nick change
-    announced.clear();
-    announce();
-});
+// Announce app, and again when nick changes
+watch([state.nick], announce);
 announce();
 
 // Ask user for nickname
@@ -129,6 +120,13 @@ announcement_events.addEventListener("AppAnnouncement", event => {
         return x;
     });
 });
+announcement_events.addEventListener("AppAnnouncementRemoved", event => {
+    const removed = JSON.parse(event.data);
+    console.log('App announcement removed', removed);
+    state.announcements.value = state.announcements.value.filter(x => {
+        return x.peer !== removed.peer || x.app_uuid !== removed.app_uuid;
+    });
+});
 
 const inbox_events = new EventSource("/_api/v1/messages/inbox/events");
 inbox_events.addEventListener("Message", event => {
@@ -38,12 +38,17 @@ interface JsonObject { [name: string]: JsonValue }
 interface JsonArray extends Array<JsonValue> { }
 type JsonValue = (null | boolean | number | string | JsonObject | JsonArray);
 
+export type Verification = "Unsigned" | "Unverifiable" | "Verified";
+
 export type Message = {
     type: "Message",
     id: number,
     peer: string,
     uuid: string,
     message: Uint8Array,
+    signature: Uint8Array | null,
+    signed_at: number | null,
+    verification: Verification,
 };
 
 export type MessageJson = {
@@ -52,13 +57,130 @@ export type MessageJson = {
     peer: string,
     uuid: string,
     message: string,
+    verification: Verification,
+};
+
+export type AddressSource = "Mdns" | "Identify" | "Dht" | "Dial";
+
+export type PeerAddress = {
+    address: string,
+    source: AddressSource,
+    last_seen: number,
+};
+
+export type PeerInfo = {
+    type: "PeerInfo",
+    peer_id: string,
+    discovered: boolean,
+    connected: boolean,
+    addresses: PeerAddress[],
+    last_seen: number | null,
+    agent_version: string | null,
+    protocol_version: string | null,
+    protocols: string[],
+    observed_address: string | null,
+    public_key: string | null,
+};
+
+export type OutboxState = "Queued" | "InFlight" | "Delivered" | "Failed";
+
+export type DeliveryError = "Unreachable"
+    | "UnknownApp"
+    | "NotAllowed"
+    | "QuotaExceeded"
+    | "UnsupportedVersion"
+    | "InternalError"
+    | "Expired";
+
+export type OutboxMessage = {
+    type: "OutboxMessage",
+    id: number,
+    peer: string,
+    uuid: string,
+    message: Uint8Array,
+    queued: number,
+    attempts: number,
+    state: OutboxState,
+    error: string | null,
+    error_kind: DeliveryError | null,
+};
+
+export type OutboxEvent = {type: "MessageDelivered", id: number, peer: string, uuid: string}
+    | {
+        type: "MessageFailed",
+        id: number,
+        peer: string,
+        uuid: string,
+        error: string,
+        error_kind: DeliveryError | null,
+        state: OutboxState,
+    };
+
+export type Invite = {
+    type: "Invite",
+    id: number,
+    peer: string,
+    app_uuid: string,
+    data: JsonValue,
+};
+
+export type InviteEvent = Invite
+    | {type: "InviteAccepted", peer: string, app_uuid: string}
+    | {type: "InviteRejected", peer: string, app_uuid: string}
+    | {
+        type: "InviteFailed",
+        peer: string,
+        app_uuid: string,
+        error: string,
+        error_kind: DeliveryError,
+    }
+    | {
+        type: "InviteResponseFailed",
+        peer: string,
+        app_uuid: string,
+        accepted: boolean,
+        error: string,
+        error_kind: DeliveryError,
+    };
+
+export type MessageAllow = {
+    peer: string,
+    app_uuid: string,
 };
 
 export type AppAnnouncement = {
     type: "AppAnnouncement",
     peer: string, 
     app_uuid: string,
+    to_app_uuid: string | null,
     data: JsonValue,
+    received: number,
+    expires: number,
+    signature: Uint8Array | null,
+    signed_at: number | null,
+    verification: Verification,
+};
+
+export type AppAnnouncementRemoved = {
+    type: "AppAnnouncementRemoved",
+    peer: string,
+    app_uuid: string,
+    to_app_uuid: string | null,
+};
+
+/// Announcements sent to no app in particular match any `to_app_uuid`.
+export type AnnouncementFilter = {
+    peer?: string,
+    app_uuid?: string,
+    to_app_uuid?: string,
+};
+
+export type TopicMessage = {
+    type: "TopicMessage",
+    peer: string,
+    app_uuid: string,
+    topic: string,
+    data: Uint8Array,
 };
 
 export type MutinyRequest = {
@@ -67,14 +189,30 @@ export type MutinyRequest = {
 };
 export type MutinyRequestBody = {type: "LocalPeerId"}
     | {type: "Peers"}
+    | {type: "PeerInfo", peer: string}
+    | {type: "ListenAddresses"}
     | {type: "DialAddress", address: string}
-    | {type: "AppAnnouncements"}
+    | {
+        type: "AppAnnouncements",
+        peer: string | null,
+        app_uuid: string | null,
+        to_app_uuid: string | null,
+        since: number | null,
+    }
     | {type: "GetLastPort", app_uuid: string}
     | {type: "SetLastPort", app_uuid: string, port: number}
     | {type: "AppInstanceUuid", label: string}
     | {type: "CreateAppInstance", label: string}
-    | {type: "Announce", peer: string, app_uuid: string, data: JsonValue}
-    | {type: "AppAnnouncements"}
+    | {
+        type: "Announce",
+        peer: string,
+        app_uuid: string,
+        to_app_uuid: string | null,
+        data: JsonValue,
+        ttl: number | null,
+    }
+    | {type: "BroadcastAnnounce", app_uuid: string, data: JsonValue, ttl: number | null}
+    | {type: "WithdrawAnnouncement", app_uuid: string}
     | {
         type: "SendMessage", 
         peer: string,
@@ -84,9 +222,42 @@ export type MutinyRequestBody = {type: "LocalPeerId"}
     }
     | {type: "InboxMessages", app_uuid: string}
     | {type: "DeleteInboxMessage", app_uuid: string, message_id: number}
+    | {type: "OutboxMessages", app_uuid: string}
+    | {type: "DeleteOutboxMessage", app_uuid: string, message_id: number}
+    | {type: "GetInboxQuota", app_uuid: string}
+    | {
+        type: "SetInboxQuota",
+        app_uuid: string,
+        max_messages: number | null,
+        max_peer_messages: number | null,
+    }
+    | {type: "RestrictMessages", app_uuid: string, restricted: boolean}
+    | {type: "AllowMessages", app_uuid: string, peer: string, from_app_uuid: string}
+    | {type: "DisallowMessages", app_uuid: string, peer: string, from_app_uuid: string}
+    | {type: "MessageAllowList", app_uuid: string}
+    | {
+        type: "SendInvite",
+        peer: string,
+        app_uuid: string,
+        from_app_uuid: string,
+        data: JsonValue,
+    }
+    | {type: "Invites", app_uuid: string}
+    | {type: "AcceptInvite", app_uuid: string, invite_id: number}
+    | {type: "RejectInvite", app_uuid: string, invite_id: number}
+    | {type: "Publish", app_uuid: string, topic: string, data: Uint8Array}
+    | {type: "SubscribeTopic", app_uuid: string, peer: string | null, publisher_uuid: string | null, topic: string}
+    | {type: "Cancel", request_id: number}
     | {type: "SubscribePeerEvents"}
-    | {type: "SubscribeAnnounceEvents"}
+    | {
+        type: "SubscribeAnnounceEvents",
+        peer: string | null,
+        app_uuid: string | null,
+        to_app_uuid: string | null,
+    }
     | {type: "SubscribeInboxEvents", app_uuid: string}
+    | {type: "SubscribeOutboxEvents", app_uuid: string}
+    | {type: "SubscribeInviteEvents", app_uuid: string}
     ;
 
 export type MutinyResponse = {
@@ -94,20 +265,63 @@ export type MutinyResponse = {
     body: MutinyResponseBody,
 };
 
+export type ConnectionDirection = "Inbound" | "Outbound";
+
+/// Sent on a subscription in place of events which were dropped
+/// because they were not read fast enough.
+export type Lagged = {type: "Lagged", missed: number};
+
 export type PeerEvent = {type: "PeerDiscovered", peer_id: string}
-    | {type: "PeerExpired", peer_id: string};
+    | {type: "PeerExpired", peer_id: string}
+    | {
+        type: "PeerConnected",
+        peer_id: string,
+        address: string,
+        direction: ConnectionDirection,
+        transport: string,
+        connections: number,
+    }
+    | {
+        type: "PeerDisconnected",
+        peer_id: string,
+        address: string,
+        direction: ConnectionDirection,
+        transport: string,
+        connections: number,
+        error: string | null,
+    }
+    | {
+        type: "PeerIdentified",
+        peer_id: string,
+        agent_version: string,
+        protocol_version: string,
+        protocols: string[],
+    };
 
 export type MutinyResponseBody = {type: "Success"} 
     | {type: "Error", message: string}
     | {type: "LocalPeerId", peer_id: string}
-    | {type: "Peers", peers: string[]}
+    | {type: "Peers", peers: PeerInfo[]}
+    | PeerInfo
+    | {type: "ListenAddresses", addresses: string[]}
+    | {type: "DialAddress", peer_id: string, protocols: string[]}
     | {type: "AppInstanceUuid", uuid: string | null}
     | {type: "GetLastPort", port: number | null}
     | {type: "CreateAppInstance", uuid: string}
     | {type: "Message", message: Message}
     | {type: "InboxMessages", messages: Message[]}
+    | {type: "SendMessage", id: number}
+    | {type: "OutboxMessages", messages: OutboxMessage[]}
+    | {type: "GetInboxQuota", max_messages: number, max_peer_messages: number}
+    | {type: "MessageAllowList", restricted: boolean, allowed: MessageAllow[]}
+    | {type: "Invites", invites: Invite[]}
+    | InviteEvent
+    | OutboxEvent
     | {type: "AppAnnouncements",  announcements: AppAnnouncement[]}
+    | AppAnnouncementRemoved
     | PeerEvent
+    | TopicMessage
+    | Lagged
     ;
 
 function sleep(ms: number): Promise<void> {
@@ -285,18 +499,31 @@ export class MutinyClient {
         return response.peer_id;
     }
 
-    async peers(): Promise<string[]> {
+    async peers(): Promise<PeerInfo[]> {
         const response = await this.requestOne({type: "Peers"});
         assert(response.type === 'Peers');
         return response.peers;
     }
 
-    async dialAddress(address: string): Promise<void> {
+    async peerInfo(peer: string): Promise<PeerInfo> {
+        const response = await this.requestOne({type: "PeerInfo", peer});
+        assert(response.type === 'PeerInfo');
+        return response;
+    }
+
+    async listenAddresses(): Promise<string[]> {
+        const response = await this.requestOne({type: "ListenAddresses"});
+        assert(response.type === 'ListenAddresses');
+        return response.addresses;
+    }
+
+    async dialAddress(address: string): Promise<{peer_id: string, protocols: string[]}> {
         const response = await this.requestOne({
             type: "DialAddress",
             address,
         });
-        assert(response.type === 'Success');
+        assert(response.type === 'DialAddress');
+        return {peer_id: response.peer_id, protocols: response.protocols};
     }
 
     private _subscribe<R>(request: MutinyRequest): AsyncIterableIterator<R> {
@@ -306,10 +533,16 @@ export class MutinyClient {
             [Symbol.asyncIterator]() {
                 return this;
             },
-            return(value?: PeerEvent) {
+            return: async (value?: R) => {
                 // Remove waiting promise
                 waiting.delete(request.id);
-                return Promise.resolve({value, done: true});
+                // Ask the daemon to stop sending events, ignoring errors
+                // if it has already ended the subscription
+                await this.requestOne({
+                    type: "Cancel",
+                    request_id: request.id,
+                }).catch(() => {});
+                return {value, done: true} as IteratorResult<R>;
             },
             next: async () => {
                 const value = await promise;
@@ -324,24 +557,60 @@ export class MutinyClient {
         };
     }
 
-    peerEvents(): AsyncIterableIterator<PeerEvent> {
+    peerEvents(): AsyncIterableIterator<PeerEvent | Lagged> {
         const body: MutinyRequestBody = {type: "SubscribePeerEvents"};
         const request = {id: this.next_request_id++, body};
         return this._subscribe(request);
     }
 
-    announceEvents(): AsyncIterableIterator<AppAnnouncement> {
-        const body: MutinyRequestBody = {type: "SubscribeAnnounceEvents"};
+    announceEvents(
+        filter: AnnouncementFilter = {}
+    ): AsyncIterableIterator<AppAnnouncement | AppAnnouncementRemoved | Lagged> {
+        const body: MutinyRequestBody = {
+            type: "SubscribeAnnounceEvents",
+            peer: filter.peer ?? null,
+            app_uuid: filter.app_uuid ?? null,
+            to_app_uuid: filter.to_app_uuid ?? null,
+        };
         const request = {id: this.next_request_id++, body};
         return this._subscribe(request);
     }
 
-    inboxEvents(app_uuid: string): AsyncIterableIterator<Message> {
+    inboxEvents(app_uuid: string): AsyncIterableIterator<Message | Lagged> {
         const body: MutinyRequestBody = {type: "SubscribeInboxEvents", app_uuid};
         const request = {id: this.next_request_id++, body};
         return this._subscribe(request);
     }
 
+    outboxEvents(app_uuid: string): AsyncIterableIterator<OutboxEvent | Lagged> {
+        const body: MutinyRequestBody = {type: "SubscribeOutboxEvents", app_uuid};
+        const request = {id: this.next_request_id++, body};
+        return this._subscribe(request);
+    }
+
+    inviteEvents(app_uuid: string): AsyncIterableIterator<InviteEvent | Lagged> {
+        const body: MutinyRequestBody = {type: "SubscribeInviteEvents", app_uuid};
+        const request = {id: this.next_request_id++, body};
+        return this._subscribe(request);
+    }
+
+    topicEvents(
+        app_uuid: string,
+        topic: string,
+        peer?: string,
+        publisher_uuid?: string,
+    ): AsyncIterableIterator<TopicMessage | Lagged> {
+        const body: MutinyRequestBody = {
+            type: "SubscribeTopic",
+            app_uuid,
+            peer: peer ?? null,
+            publisher_uuid: publisher_uuid ?? null,
+            topic,
+        };
+        const request = {id: this.next_request_id++, body};
+        return this._subscribe(request);
+    }
+
     async appInstanceUuid(label: string): Promise<string | null> {
         const response = await this.requestOne({type: "AppInstanceUuid", label});
         assert(response.type === 'AppInstanceUuid');
@@ -365,23 +634,65 @@ export class MutinyClient {
         return response.uuid;
     }
 
-    async announce(peer: string, app_uuid: string, data: JsonValue): Promise<void> {
-        const response = await this.requestOne({type: "Announce", peer, app_uuid, data});
+    async announce(
+        peer: string,
+        app_uuid: string,
+        data: JsonValue,
+        ttl?: number,
+        to_app_uuid?: string,
+    ): Promise<void> {
+        const response = await this.requestOne({
+            type: "Announce",
+            peer,
+            app_uuid,
+            to_app_uuid: to_app_uuid ?? null,
+            data,
+            ttl: ttl ?? null,
+        });
         assert(response.type === 'Success');
     }
 
-    async announcements(): Promise<AppAnnouncement[]> {
-        const response = await this.requestOne({type: "AppAnnouncements"});
+    async broadcastAnnounce(app_uuid: string, data: JsonValue, ttl?: number): Promise<void> {
+        const response = await this.requestOne({
+            type: "BroadcastAnnounce",
+            app_uuid,
+            data,
+            ttl: ttl ?? null,
+        });
+        assert(response.type === 'Success');
+    }
+
+    async withdrawAnnouncement(app_uuid: string): Promise<void> {
+        const response = await this.requestOne({type: "WithdrawAnnouncement", app_uuid});
+        assert(response.type === 'Success');
+    }
+
+    async announcements(
+        filter: AnnouncementFilter = {},
+        since?: number,
+    ): Promise<AppAnnouncement[]> {
+        const response = await this.requestOne({
+            type: "AppAnnouncements",
+            peer: filter.peer ?? null,
+            app_uuid: filter.app_uuid ?? null,
+            to_app_uuid: filter.to_app_uuid ?? null,
+            since: since ?? null,
+        });
         assert(response.type === 'AppAnnouncements');
         return response.announcements;
     }
 
+    async publish(app_uuid: string, topic: string, data: Uint8Array): Promise<void> {
+        const response = await this.requestOne({type: "Publish", app_uuid, topic, data});
+        assert(response.type === 'Success');
+    }
+
     async sendMessage(
         peer: string,
         app_uuid: string,
         from_app_uuid: string,
         message: Uint8Array
-    ): Promise<void> {
+    ): Promise<number> {
         const response = await this.requestOne({
             type: "SendMessage", 
             peer, 
@@ -389,8 +700,8 @@ export class MutinyClient {
             from_app_uuid,
             message,
         });
-        assert(response.type === 'Success');
-        return;
+        assert(response.type === 'SendMessage');
+        return response.id;
     }
 
     async inboxMessages(app_uuid: string): Promise<Message[]> {
@@ -404,4 +715,102 @@ export class MutinyClient {
         assert(response.type === 'Success');
         return;
     }
+
+    async getInboxQuota(app_uuid: string): Promise<{max_messages: number, max_peer_messages: number}> {
+        const response = await this.requestOne({type: "GetInboxQuota", app_uuid});
+        assert(response.type === 'GetInboxQuota');
+        return {
+            max_messages: response.max_messages,
+            max_peer_messages: response.max_peer_messages,
+        };
+    }
+
+    async setInboxQuota(
+        app_uuid: string,
+        max_messages: number | null,
+        max_peer_messages: number | null,
+    ): Promise<void> {
+        const response = await this.requestOne({
+            type: "SetInboxQuota",
+            app_uuid,
+            max_messages,
+            max_peer_messages,
+        });
+        assert(response.type === 'Success');
+    }
+
+    async restrictMessages(app_uuid: string, restricted: boolean): Promise<void> {
+        const response = await this.requestOne({type: "RestrictMessages", app_uuid, restricted});
+        assert(response.type === 'Success');
+    }
+
+    async allowMessages(app_uuid: string, peer: string, from_app_uuid: string): Promise<void> {
+        const response = await this.requestOne({
+            type: "AllowMessages",
+            app_uuid,
+            peer,
+            from_app_uuid,
+        });
+        assert(response.type === 'Success');
+    }
+
+    async disallowMessages(app_uuid: string, peer: string, from_app_uuid: string): Promise<void> {
+        const response = await this.requestOne({
+            type: "DisallowMessages",
+            app_uuid,
+            peer,
+            from_app_uuid,
+        });
+        assert(response.type === 'Success');
+    }
+
+    async messageAllowList(app_uuid: string): Promise<{restricted: boolean, allowed: MessageAllow[]}> {
+        const response = await this.requestOne({type: "MessageAllowList", app_uuid});
+        assert(response.type === 'MessageAllowList');
+        return {restricted: response.restricted, allowed: response.allowed};
+    }
+
+    async sendInvite(
+        peer: string,
+        app_uuid: string,
+        from_app_uuid: string,
+        data: JsonValue,
+    ): Promise<void> {
+        const response = await this.requestOne({
+            type: "SendInvite",
+            peer,
+            app_uuid,
+            from_app_uuid,
+            data,
+        });
+        assert(response.type === 'Success');
+    }
+
+    async invites(app_uuid: string): Promise<Invite[]> {
+        const response = await this.requestOne({type: "Invites", app_uuid});
+        assert(response.type === 'Invites');
+        return response.invites;
+    }
+
+    async acceptInvite(app_uuid: string, invite_id: number): Promise<void> {
+        const response = await this.requestOne({type: "AcceptInvite", app_uuid, invite_id});
+        assert(response.type === 'Success');
+    }
+
+    async rejectInvite(app_uuid: string, invite_id: number): Promise<void> {
+        const response = await this.requestOne({type: "RejectInvite", app_uuid, invite_id});
+        assert(response.type === 'Success');
+    }
+
+    async outboxMessages(app_uuid: string): Promise<OutboxMessage[]> {
+        const response = await this.requestOne({type: "OutboxMessages", app_uuid});
+        assert(response.type === 'OutboxMessages');
+        return response.messages;
+    }
+
+    async deleteOutboxMessage(app_uuid: string, message_id: number): Promise<void> {
+        const response = await this.requestOne({type: "DeleteOutboxMessage", app_uuid, message_id});
+        assert(response.type === 'Success');
+        return;
+    }
 }
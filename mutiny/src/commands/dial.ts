@@ -11,5 +11,9 @@ export default async function (args: ReturnType<typeof parseArgs>) {
     const address = "" + args._[0];
 
     const client = new MutinyClient({socket_path});
-    await client.dialAddress(address);
+    const {peer_id, protocols} = await client.dialAddress(address);
+    console.log(`Connected to ${peer_id}`);
+    for (const protocol of protocols) {
+        console.log(`  ${protocol}`);
+    }
 }
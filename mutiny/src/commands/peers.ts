@@ -12,6 +12,6 @@ export default async function (args: ReturnType<typeof parseArgs>) {
     const client = new MutinyClient({socket_path});
     const peers = await client.peers();
     for (const peer of peers) {
-        console.log(peer);
+        console.log(peer.peer_id);
     }
 }
@@ -1,4 +1,10 @@
-export default function eventStream<T>(iter: AsyncIterableIterator<T>, map: (event: T) => [string, string]) {
+import type { Lagged } from "./client.ts";
+
+function isLagged(event: unknown): event is Lagged {
+    return (event as Lagged).type === 'Lagged';
+}
+
+export default function eventStream<T>(iter: AsyncIterableIterator<T | Lagged>, map: (event: T) => [string, string]) {
     let stop = false;
     const body = new ReadableStream({
         start(controller) {
@@ -7,7 +13,9 @@ export default function eventStream<T>(iter: AsyncIterableIterator<T>, map: (eve
                 try {
                     for await (const event of iter) {
                         if (stop) break;
-                        const [name, data] = map(event);
+                        const [name, data] = isLagged(event)
+                            ? ['Lagged', String(event.missed)]
+                            : map(event);
                         controller.enqueue(
                             encoder.encode(
                                 `event: ${name}\r\ndata: ${data}\r\n\r\n`
@@ -23,6 +31,8 @@ export default function eventStream<T>(iter: AsyncIterableIterator<T>, map: (eve
         },
         cancel() {
             stop = true;
+            // End the daemon subscription now rather than on the next event
+            iter.return?.();
         }
     });
     return new Response(body, {
@@ -1,4 +1,4 @@
-import { MutinyClient } from "./client.ts";
+import { MutinyClient, type AnnouncementFilter } from "./client.ts";
 import { serveDir } from "@std/http";
 import eventStream from "./eventstream.ts";
 
@@ -17,6 +17,16 @@ export class Server {
         return new RegExp('^/_api(?:/.*)?$').test(pathname);
     }
 
+    // Apps only see announcements sent to no app in particular, or to
+    // the app itself
+    announcementFilter(url: URL): AnnouncementFilter {
+        return {
+            peer: url.searchParams.get('peer') ?? undefined,
+            app_uuid: url.searchParams.get('app_uuid') ?? undefined,
+            to_app_uuid: this.app.uuid,
+        };
+    }
+
     async serveAPI(request: Request) {
         try {
             const url = new URL(request.url);
@@ -25,43 +35,156 @@ export class Server {
                 return new Response(JSON.stringify(this.app));
             } else if (pathname === '/_api/v1/local_peer_id') {
                 return new Response(await this.client.localPeerId());
+            } else if (pathname === '/_api/v1/listen_addresses') {
+                return new Response(JSON.stringify(await this.client.listenAddresses()));
             } else if (pathname === '/_api/v1/peers') {
+                const peers = await this.client.peers();
+                return new Response(JSON.stringify(peers.map(peer => peer.peer_id)));
+            } else if (pathname === '/_api/v1/peers/info') {
+                const peer = url.searchParams.get('peer');
+                if (peer) {
+                    return new Response(JSON.stringify(await this.client.peerInfo(peer)));
+                }
                 return new Response(JSON.stringify(await this.client.peers()));
             } else if (pathname === '/_api/v1/peers/events') {
                 return eventStream(this.client.peerEvents(), event => {
-                    return [event.type, event.peer_id];
+                    if (event.type === 'PeerDiscovered' || event.type === 'PeerExpired') {
+                        return [event.type, event.peer_id];
+                    }
+                    const {type, ...data} = event;
+                    return [type, JSON.stringify(data)];
                 });
             } else if (request.method === 'POST' && pathname === '/_api/v1/dial') {
                 const body = await request.json();
-                await this.client.dialAddress(body.address);
-                return new Response(JSON.stringify({success: true}));
+                const {peer_id, protocols} = await this.client.dialAddress(body.address);
+                return new Response(JSON.stringify({success: true, peer_id, protocols}));
             } else if (request.method === 'POST' && pathname === '/_api/v1/announcements/outbox') {
                 const body = await request.json();
                 await this.client.announce(
                     body.peer,
                     this.app.uuid,
                     body.data,
+                    body.ttl,
+                    body.to_app_uuid,
                 );
                 return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/announcements/broadcast') {
+                const body = await request.json();
+                await this.client.broadcastAnnounce(this.app.uuid, body.data, body.ttl);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/announcements/withdraw') {
+                await this.client.withdrawAnnouncement(this.app.uuid);
+                return new Response(JSON.stringify({success: true}));
             } else if (pathname === '/_api/v1/announcements/inbox') {
-                return new Response(JSON.stringify(await this.client.announcements()));
+                const since = url.searchParams.get('since');
+                const announcements = await this.client.announcements(
+                    this.announcementFilter(url),
+                    since === null ? undefined : Number(since),
+                );
+                return new Response(JSON.stringify(announcements.map(a => ({
+                    peer: a.peer,
+                    app_uuid: a.app_uuid,
+                    data: a.data,
+                    received: a.received,
+                    expires: a.expires,
+                    verification: a.verification,
+                }))));
             } else if (pathname === '/_api/v1/announcements/inbox/events') {
-                return eventStream(this.client.announceEvents(), event => {
+                const filter = this.announcementFilter(url);
+                return eventStream(this.client.announceEvents(filter), event => {
+                    if (event.type === 'AppAnnouncementRemoved') {
+                        return [event.type, JSON.stringify({
+                            peer: event.peer,
+                            app_uuid: event.app_uuid,
+                        })];
+                    }
                     return [event.type, JSON.stringify({
-                        peer: event.peer, 
+                        peer: event.peer,
                         app_uuid: event.app_uuid,
                         data: event.data,
+                        received: event.received,
+                        expires: event.expires,
+                        verification: event.verification,
                     })];
                 });
             } else if (request.method === 'POST' && pathname === '/_api/v1/messages/outbox') {
                 const body = await request.json();
                 const message = new TextEncoder().encode(body.message);
-                return new Response(JSON.stringify(await this.client.sendMessage(
+                const id = await this.client.sendMessage(
                     body.peer,
                     body.app_uuid,
                     this.app.uuid,
                     message,
-                )));
+                );
+                return new Response(JSON.stringify({id}));
+            } else if (request.method === 'GET' && pathname === '/_api/v1/messages/outbox') {
+                const messages = await this.client.outboxMessages(this.app.uuid);
+                return new Response(JSON.stringify(messages.map(m => ({
+                    id: m.id,
+                    peer: m.peer,
+                    uuid: m.uuid,
+                    message: new TextDecoder().decode(m.message),
+                    queued: m.queued,
+                    attempts: m.attempts,
+                    state: m.state,
+                    error: m.error,
+                    error_kind: m.error_kind,
+                }))));
+            } else if (request.method === 'DELETE' && pathname === '/_api/v1/messages/outbox') {
+                const body = await request.json();
+                await this.client.deleteOutboxMessage(this.app.uuid, body.message_id);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'GET' && pathname === '/_api/v1/messages/allow') {
+                return new Response(JSON.stringify(
+                    await this.client.messageAllowList(this.app.uuid)
+                ));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/messages/allow') {
+                const body = await request.json();
+                await this.client.allowMessages(this.app.uuid, body.peer, body.app_uuid);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'DELETE' && pathname === '/_api/v1/messages/allow') {
+                const body = await request.json();
+                await this.client.disallowMessages(this.app.uuid, body.peer, body.app_uuid);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/messages/restrict') {
+                const body = await request.json();
+                await this.client.restrictMessages(this.app.uuid, body.restricted);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/invites/outbox') {
+                const body = await request.json();
+                await this.client.sendInvite(
+                    body.peer,
+                    body.app_uuid,
+                    this.app.uuid,
+                    body.data,
+                );
+                return new Response(JSON.stringify({success: true}));
+            } else if (pathname === '/_api/v1/invites/inbox') {
+                const invites = await this.client.invites(this.app.uuid);
+                return new Response(JSON.stringify(invites.map(i => ({
+                    id: i.id,
+                    peer: i.peer,
+                    app_uuid: i.app_uuid,
+                    data: i.data,
+                }))));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/invites/inbox/accept') {
+                const body = await request.json();
+                await this.client.acceptInvite(this.app.uuid, body.invite_id);
+                return new Response(JSON.stringify({success: true}));
+            } else if (request.method === 'POST' && pathname === '/_api/v1/invites/inbox/reject') {
+                const body = await request.json();
+                await this.client.rejectInvite(this.app.uuid, body.invite_id);
+                return new Response(JSON.stringify({success: true}));
+            } else if (pathname === '/_api/v1/invites/events') {
+                return eventStream(this.client.inviteEvents(this.app.uuid), event => {
+                    const {type, ...data} = event;
+                    return [type, JSON.stringify(data)];
+                });
+            } else if (pathname === '/_api/v1/messages/outbox/events') {
+                return eventStream(this.client.outboxEvents(this.app.uuid), event => {
+                    const {type, ...data} = event;
+                    return [type, JSON.stringify(data)];
+                });
             } else if (request.method === 'GET' && pathname === '/_api/v1/messages/inbox') {
                 const messages = await this.client.inboxMessages(this.app.uuid);
                 return new Response(JSON.stringify(messages.map(m => ({
@@ -69,6 +192,7 @@ export class Server {
                     peer: m.peer,
                     uuid: m.uuid,
                     message: new TextDecoder().decode(m.message),
+                    verification: m.verification,
                 }))));
             } else if (request.method === 'DELETE' && pathname === '/_api/v1/messages/inbox') {
                 const body = await request.json();
@@ -81,6 +205,25 @@ export class Server {
                         peer: event.peer, 
                         uuid: event.uuid,
                         message: new TextDecoder().decode(event.message),
+                        verification: event.verification,
+                    })];
+                });
+            } else if (request.method === 'POST' && pathname === '/_api/v1/topics/publish') {
+                const body = await request.json();
+                const data = new TextEncoder().encode(body.data);
+                await this.client.publish(this.app.uuid, body.topic, data);
+                return new Response(JSON.stringify({success: true}));
+            } else if (pathname === '/_api/v1/topics/events') {
+                const topic = url.searchParams.get('topic') ?? '';
+                const peer = url.searchParams.get('peer') ?? undefined;
+                const publisher_uuid = url.searchParams.get('app_uuid') ?? undefined;
+                const events = this.client.topicEvents(this.app.uuid, topic, peer, publisher_uuid);
+                return eventStream(events, event => {
+                    return [event.type, JSON.stringify({
+                        peer: event.peer,
+                        app_uuid: event.app_uuid,
+                        topic: event.topic,
+                        data: new TextDecoder().decode(event.data),
                     })];
                 });
             } else {
@@ -1,6 +1,6 @@
 import { join } from "@std/path/join";
 import { assertEquals } from "@std/assert";
-import { MutinyClient } from "../src/client.ts";
+import { AnnouncementFilter, MutinyClient, PeerInfo } from "../src/client.ts";
 import { Server }from "../src/server.ts";
 
 const BASE_URL = "http://localhost:8000";
@@ -35,11 +35,27 @@ Deno.test("Get local peer id", async () => {
     assertEquals(data, "123abc");
 });
 
+function makePeerInfo(peer_id: string): PeerInfo {
+    return {
+        type: "PeerInfo",
+        peer_id,
+        discovered: true,
+        connected: false,
+        addresses: [],
+        last_seen: null,
+        agent_version: null,
+        protocol_version: null,
+        protocols: [],
+        observed_address: null,
+        public_key: null,
+    };
+}
+
 Deno.test("Get peers list", async () => {
     const peers = ["peer1", "peer2", "peer3"];
     const server = makeServer({
         peers() {
-            return Promise.resolve(peers);
+            return Promise.resolve(peers.map(makePeerInfo));
         },
     });
     const request = new Request(`${BASE_URL}/_api/v1/peers`);
@@ -48,6 +64,21 @@ Deno.test("Get peers list", async () => {
     assertEquals(data, peers);
 });
 
+Deno.test("Get peer info", async () => {
+    const calls: string[] = [];
+    const server = makeServer({
+        peerInfo(peer: string) {
+            calls.push(peer);
+            return Promise.resolve(makePeerInfo(peer));
+        },
+    });
+    const request = new Request(`${BASE_URL}/_api/v1/peers/info?peer=peer1`);
+    const response = await server.handleRequest(request);
+    const data = await response.json();
+    assertEquals(data, makePeerInfo("peer1"));
+    assertEquals(calls, ["peer1"]);
+});
+
 Deno.test("Send app announcement", async () => {
     const calls: [string, string, unknown][] = [];
     const server = makeServer({
@@ -89,15 +120,19 @@ Deno.test("List app announcements", async () => {
             }
         },
     ];
+    const calls: AnnouncementFilter[] = [];
     const server = makeServer({
-        announcements() {
+        announcements(filter: AnnouncementFilter) {
+            calls.push(filter);
             return Promise.resolve(announcements);
         },
     });
-    const request = new Request(`${BASE_URL}/_api/v1/announcements/inbox`);
+    const request = new Request(`${BASE_URL}/_api/v1/announcements/inbox?peer=peer1`);
     const response = await server.handleRequest(request);
     const data = await response.json();
     assertEquals(data, announcements);
+    // Announcements sent to other apps are never listed
+    assertEquals(calls, [{peer: "peer1", app_uuid: undefined, to_app_uuid: APP.uuid}]);
 });
 
 Deno.test("Read inbox (with message)", async () => {
@@ -105,6 +140,7 @@ Deno.test("Read inbox (with message)", async () => {
         peer: "peer2",
         uuid: "app2",
         message: new TextEncoder().encode("hello"),
+        verification: "Verified",
     };
     const server = makeServer({
         inboxMessages(uuid: string) {
@@ -119,6 +155,7 @@ Deno.test("Read inbox (with message)", async () => {
         peer: "peer2",
         uuid: "app2",
         message: "hello",
+        verification: "Verified",
     }]);
 });
 
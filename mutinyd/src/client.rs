@@ -8,33 +8,44 @@ use error_set::error_set;
 use crate::protocol::{Request, Response, ResponseBody};
 
 pub struct ClientRequest {
+    /// Identifies the connection the request was made on, request ids
+    /// are only unique per connection.
+    pub client_id: usize,
     pub request: Request,
     pub response: mpsc::Sender<ResponseBody>,
 }
 
+pub enum ClientEvent {
+    Request(ClientRequest),
+    /// The client connection closed, any subscriptions it made can be
+    /// removed.
+    Disconnected {
+        client_id: usize,
+    },
+}
+
 struct RequestHandler {
-    pub request_sender: mpsc::Sender<ClientRequest>,
+    pub request_id: usize,
+    pub receiver: mpsc::Receiver<ResponseBody>,
     pub responses_tx: mpsc::Sender<Response>,
 }
 
 impl RequestHandler {
-    async fn start(self, request: Request) -> () {
-        let (tx, mut rx) = mpsc::channel(100);
-        let request_id = request.id;
-        let result = self.request_sender.send(ClientRequest {
-            request: request,
-            response: tx,
-        }).await;
-        println!("Request sent for handling");
-        if let Err(err) = result {
-            eprintln!("Error sending client request for handling: {}", err);
-            return;
-        }
-        while let Some(body) = rx.recv().await {
-            println!("Response body received from handler {:?}", body);
-            if let Err(err) = self.responses_tx.send(Response {request_id, body}).await {
-                eprintln!("Error queuing response for client: {}", err);
-                return;
+    async fn start(mut self) -> () {
+        let request_id = self.request_id;
+        loop {
+            tokio::select! {
+                body = self.receiver.recv() => {
+                    let Some(body) = body else { break };
+                    println!("Response body received from handler {:?}", body);
+                    if let Err(err) = self.responses_tx.send(Response {request_id, body}).await {
+                        eprintln!("Error queuing response for client: {}", err);
+                        return;
+                    }
+                },
+                // Stop as soon as the client goes away so the server
+                // sees the subscription as closed
+                _ = self.responses_tx.closed() => break,
             }
         }
         println!("Request handler stopped {}", request_id);
@@ -42,18 +53,21 @@ impl RequestHandler {
 }
 
 pub struct Client<Reader: AsyncReadExt + Unpin, Writer: AsyncWrite + AsyncWriteExt + Unpin> {
-    request_sender: mpsc::Sender<ClientRequest>,
+    id: usize,
+    request_sender: mpsc::Sender<ClientEvent>,
     reader: Reader,
     writer: Writer,
 }
 
 error_set! {
+    // Variant names match the error types they wrap
+    #[allow(clippy::enum_variant_names)]
     ClientError = {
         IoError(std::io::Error),
         DecodeRequestError(rmp_serde::decode::Error),
         EncodeResponseError(rmp_serde::encode::Error),
         ReadLengthError(std::num::TryFromIntError),
-        SendRequestError(mpsc::error::SendError<ClientRequest>),
+        SendRequestError(mpsc::error::SendError<ClientEvent>),
     };
 }
 
@@ -78,24 +92,61 @@ impl<Reader: AsyncReadExt + Unpin, Writer: AsyncWrite + AsyncWriteExt + Unpin> C
         Ok(())
     }
 
-    fn spawn_request_handler(&mut self, request: Request, responses_tx: mpsc::Sender<Response>) {
+    fn spawn_request_handler(
+        &mut self,
+        request: Request,
+        responses_tx: mpsc::Sender<Response>,
+        queue: &mpsc::UnboundedSender<ClientEvent>,
+    ) {
         println!("Spawning request handler for {:?}", request);
+        let (tx, rx) = mpsc::channel(100);
         let handler = RequestHandler {
-            request_sender: self.request_sender.clone(),
-            responses_tx: responses_tx.clone(),
+            request_id: request.id,
+            receiver: rx,
+            responses_tx,
         };
-        tokio::spawn(handler.start(request));
+        tokio::spawn(handler.start());
+        // Fails only if the server has stopped, in which case the
+        // handler will stop too as its sender is dropped
+        let _ = queue.send(ClientEvent::Request(ClientRequest {
+            client_id: self.id,
+            request,
+            response: tx,
+        }));
     }
 
-    // TODO: split into separate read requests / write response loops
     pub async fn start(mut self) -> () {
+        // Requests are forwarded to the server from a single task so
+        // they are handled in the order they were received, e.g. a
+        // Cancel after the subscription it ends, and the disconnect
+        // after all of them.
+        let (queue_tx, mut queue_rx) = mpsc::unbounded_channel();
+        let request_sender = self.request_sender.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(event) = queue_rx.recv().await {
+                if let Err(err) = request_sender.send(event).await {
+                    eprintln!("Error sending client request for handling: {}", err);
+                    return;
+                }
+            }
+        });
+        self.run(&queue_tx).await;
+        let _ = queue_tx.send(ClientEvent::Disconnected {client_id: self.id});
+        drop(queue_tx);
+        if let Err(err) = forwarder.await {
+            eprintln!("Client request forwarder failed: {}", err);
+        }
+    }
+
+    // TODO: split into separate read requests / write response loops
+    async fn run(&mut self, queue: &mpsc::UnboundedSender<ClientEvent>) -> () {
         let (responses_tx, mut responses_rx) = mpsc::channel(100);
         loop {
             tokio::select! {
                 req = self.read_request() => {
                     match req {
                         Ok(request) => {
-                            self.spawn_request_handler(request, responses_tx.clone());
+                            self.spawn_request_handler(request, responses_tx.clone(), queue);
                         },
                         Err(err) => {
                             if let ClientError::IoError(e) = err {
@@ -142,22 +193,31 @@ impl<Reader: AsyncReadExt + Unpin, Writer: AsyncWrite + AsyncWriteExt + Unpin> C
 
 pub fn create_client(
     stream: UnixStream,
-    request_sender: mpsc::Sender<ClientRequest>,
+    id: usize,
+    request_sender: mpsc::Sender<ClientEvent>,
 ) -> Client<BufReader<tokio::net::unix::OwnedReadHalf>, tokio::net::unix::OwnedWriteHalf> {
     let (reader, writer) = stream.into_split();
-    return Client {
+    Client {
+        id,
         request_sender,
         reader: BufReader::new(reader),
         writer,
-    };
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::RequestBody;
+    use crate::protocol::{RequestBody, PeerInfo, PeerAddress, AddressSource};
     use tokio::time::{timeout, sleep, Duration};
 
+    fn expect_request(event: ClientEvent) -> ClientRequest {
+        match event {
+            ClientEvent::Request(request) => request,
+            ClientEvent::Disconnected {..} => panic!("Expected a client request"),
+        }
+    }
+
     #[tokio::test]
     async fn decode_request_and_send_over_channel() {
         let response = Vec::new();
@@ -177,13 +237,19 @@ mod tests {
             request.write_all(&serialized).await.unwrap();
 
             let client = Client {
+                id: 1,
                 request_sender: tx,
                 reader: request.as_slice(),
                 writer: response,
             };
             client.start().await;
         });
-        let message = rx.recv().await.unwrap();
+        let message = expect_request(rx.recv().await.unwrap());
+        // The reader ends after the request
+        match rx.recv().await.unwrap() {
+            ClientEvent::Disconnected {client_id} => assert_eq!(client_id, 1),
+            ClientEvent::Request(_) => panic!("Expected client disconnect"),
+        }
         handle.abort();
         assert_eq!(message.request, Request {
             id: 1,
@@ -211,6 +277,7 @@ mod tests {
             request_writer.write_all(&serialized).await.unwrap();
 
             let client = Client {
+                id: 1,
                 request_sender: tx,
                 reader: request_reader,
                 writer: response_writer,
@@ -218,7 +285,7 @@ mod tests {
             client.start().await;
         });
 
-        let message = rx.recv().await.unwrap();
+        let message = expect_request(rx.recv().await.unwrap());
         assert_eq!(message.request, Request {
             id: 2,
             body: RequestBody::LocalPeerId,
@@ -285,6 +352,7 @@ mod tests {
             });
 
             let client = Client {
+                id: 1,
                 request_sender: tx,
                 reader: request_reader,
                 writer: response_writer,
@@ -293,14 +361,14 @@ mod tests {
         });
 
         // Read first request
-        let message1 = timeout(Duration::from_millis(1000), rx.recv()).await.unwrap().unwrap();
+        let message1 = expect_request(timeout(Duration::from_millis(1000), rx.recv()).await.unwrap().unwrap());
         assert_eq!(message1.request, Request {
             id: 1,
             body: RequestBody::LocalPeerId,
         });
 
         // Read second request
-        let message2 = timeout(Duration::from_millis(1000), rx.recv()).await.unwrap().unwrap();
+        let message2 = expect_request(timeout(Duration::from_millis(1000), rx.recv()).await.unwrap().unwrap());
         assert_eq!(message2.request, Request {
             id: 2,
             body: RequestBody::Peers,
@@ -314,7 +382,22 @@ mod tests {
             let res = Response {
                 request_id: 2,
                 body: ResponseBody::Peers {
-                    peers: vec![String::from("peer2")],
+                    peers: vec![PeerInfo {
+                        peer_id: String::from("peer2"),
+                        discovered: true,
+                        connected: false,
+                        addresses: vec![PeerAddress {
+                            address: String::from("/ip4/192.168.1.2/tcp/4001"),
+                            source: AddressSource::Mdns,
+                            last_seen: 100,
+                        }],
+                        last_seen: Some(100),
+                        agent_version: None,
+                        protocol_version: None,
+                        protocols: vec![],
+                        observed_address: None,
+                        public_key: None,
+                    }],
                 },
             };
             // Serialize response
@@ -1,16 +1,23 @@
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::error::Error;
-use libp2p::identity::Keypair;
+use libp2p::{identity::Keypair, Multiaddr};
+use serde::Deserialize;
 use std::io::Write;
 use std::fs;
-use rusqlite;
+
+use crate::swarm::{self, Transport};
+
+/// Upper limit on durations in seconds (about 100 years), so they can be
+/// added to timestamps and instants without overflowing.
+const MAX_SECS: u64 = 100 * 365 * 24 * 60 * 60;
 
 #[derive(Debug)]
 pub struct Config {
     pub keypair: Keypair,
     pub socket_path: PathBuf,
     pub db_connection: rusqlite::Connection,
+    pub settings: Settings,
 }
 
 impl Config {
@@ -18,6 +25,7 @@ impl Config {
         keypair_path: PathBuf,
         socket_path: PathBuf,
         db_path: PathBuf,
+        settings: Settings,
     ) -> Result<Self, Box<dyn Error>> {
         println!("Reading identity {:?}", keypair_path);
         let keypair = if keypair_path.exists() {
@@ -33,6 +41,235 @@ impl Config {
             k
         };
         let db_connection = rusqlite::Connection::open(db_path)?;
-        Ok(Self { keypair, socket_path, db_connection })
+        Ok(Self { keypair, socket_path, db_connection, settings })
+    }
+}
+
+/// Settings read from the config file (`config.toml` in the data
+/// directory by default). Missing sections and keys use their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub network: NetworkSettings,
+    pub storage: StorageSettings,
+    pub limits: LimitSettings,
+    pub logging: LogSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    /// Transports to listen on using a random port, ignored if `listen`
+    /// is not empty.
+    pub transports: Vec<Transport>,
+    /// Addresses to listen on, e.g. "/ip4/0.0.0.0/tcp/4001".
+    pub listen: Vec<Multiaddr>,
+    /// Transports to dial known addresses of peers over, most preferred
+    /// first. Addresses using other transports are skipped.
+    pub dial_transports: Vec<Transport>,
+    /// Seconds before closing a connection with no active streams.
+    pub idle_connection_timeout_secs: u64,
+    /// Discover peers on the local network using multicast DNS.
+    pub mdns: bool,
+    /// Peers to join the DHT through, e.g.
+    /// "/ip4/203.0.113.1/tcp/4001/p2p/12D3KooW...".
+    pub bootstrap: Vec<Multiaddr>,
+    /// How often to refresh the DHT routing table via the bootstrap peers.
+    pub bootstrap_interval_secs: u64,
+    /// Time to wait for a requested dial to connect and identify the peer.
+    pub dial_timeout_secs: u64,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            transports: vec![Transport::Quic, Transport::Tcp],
+            listen: Vec::new(),
+            dial_transports: vec![Transport::Quic, Transport::Tcp],
+            idle_connection_timeout_secs: 60,
+            mdns: true,
+            bootstrap: Vec::new(),
+            bootstrap_interval_secs: 5 * 60,
+            dial_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    /// How often to remove unreferenced data and compact the database.
+    pub compact_interval_secs: u64,
+    /// How often to delete messages older than `message_max_age_secs`.
+    pub sweep_interval_secs: u64,
+    /// Age after which inbox and outbox messages are deleted. Must be at
+    /// least `limits.delivery_expiry_secs`.
+    pub message_max_age_secs: i64,
+    /// Time since a peer's address was last seen after which it is no
+    /// longer redialed and is deleted.
+    pub peer_address_max_age_secs: i64,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            compact_interval_secs: 24 * 60 * 60,
+            sweep_interval_secs: 60 * 60,
+            message_max_age_secs: 30 * 24 * 60 * 60,
+            peer_address_max_age_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Default maximum number of messages in an app's inbox.
+    pub inbox_max_messages: u32,
+    /// Default maximum number of messages in an app's inbox from a
+    /// single peer.
+    pub inbox_max_peer_messages: u32,
+    /// Maximum number of pending invites from a single peer.
+    pub invite_max_peer_invites: u32,
+    /// Number of delivery attempts before a message is marked as failed.
+    pub delivery_max_attempts: u32,
+    /// Time after queuing when an undelivered message is marked as failed.
+    pub delivery_expiry_secs: i64,
+    /// Number of events a subscriber can fall behind before it starts
+    /// missing them.
+    pub event_buffer_size: usize,
+    /// Longest time a received announcement is kept, and how long it
+    /// is kept if the sender doesn't say.
+    pub announcement_max_ttl_secs: i64,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            inbox_max_messages: 10_000,
+            inbox_max_peer_messages: 1_000,
+            invite_max_peer_invites: 100,
+            delivery_max_attempts: 20,
+            delivery_expiry_secs: 7 * 24 * 60 * 60,
+            event_buffer_size: 1_000,
+            announcement_max_ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// Log routine network activity (mDNS, connections, identify).
+    pub verbose: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self { verbose: true }
+    }
+}
+
+impl Settings {
+    /// Reads settings from a TOML file, returning the defaults if the
+    /// file does not exist.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        println!("Reading config {:?}", path);
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Checks for values that would leave the daemon unable to run.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.network.idle_connection_timeout_secs > MAX_SECS {
+            return Err(format!("network.idle_connection_timeout_secs must be at most {MAX_SECS}").into());
+        }
+        if self.network.transports.is_empty() && self.network.listen.is_empty() {
+            return Err("network.transports and network.listen are both empty".into());
+        }
+        if self.network.dial_transports.is_empty() {
+            return Err("network.dial_transports must not be empty".into());
+        }
+        for addr in &self.network.bootstrap {
+            if swarm::split_peer_id(addr.clone()).is_none() {
+                return Err(format!("network.bootstrap address {addr} must end with /p2p/<peer id>").into());
+            }
+        }
+        check_secs("network.bootstrap_interval_secs", self.network.bootstrap_interval_secs)?;
+        check_secs("network.dial_timeout_secs", self.network.dial_timeout_secs)?;
+        check_secs("storage.compact_interval_secs", self.storage.compact_interval_secs)?;
+        check_secs("storage.sweep_interval_secs", self.storage.sweep_interval_secs)?;
+        check_secs("storage.message_max_age_secs", self.storage.message_max_age_secs.try_into().unwrap_or(0))?;
+        check_secs("storage.peer_address_max_age_secs", self.storage.peer_address_max_age_secs.try_into().unwrap_or(0))?;
+        if self.limits.delivery_max_attempts == 0 {
+            return Err("limits.delivery_max_attempts must be greater than zero".into());
+        }
+        check_secs("limits.delivery_expiry_secs", self.limits.delivery_expiry_secs.try_into().unwrap_or(0))?;
+        // Otherwise queued messages would be deleted before they fail
+        if self.storage.message_max_age_secs < self.limits.delivery_expiry_secs {
+            return Err("storage.message_max_age_secs must not be less than limits.delivery_expiry_secs".into());
+        }
+        if self.limits.event_buffer_size == 0 {
+            return Err("limits.event_buffer_size must be greater than zero".into());
+        }
+        check_secs("limits.announcement_max_ttl_secs", self.limits.announcement_max_ttl_secs.try_into().unwrap_or(0))?;
+        Ok(())
+    }
+}
+
+fn check_secs(name: &str, secs: u64) -> Result<(), Box<dyn Error>> {
+    if secs == 0 {
+        return Err(format!("{name} must be greater than zero").into());
+    }
+    if secs > MAX_SECS {
+        return Err(format!("{name} must be at most {MAX_SECS}").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        assert_eq!(Settings::parse("").unwrap(), Settings::default());
+        let settings = Settings::parse(r#"
+            [network]
+            listen = ["/ip4/0.0.0.0/tcp/4001"]
+            mdns = false
+
+            [limits]
+            inbox_max_messages = 50
+        "#).unwrap();
+        assert_eq!(settings.network.listen, vec!["/ip4/0.0.0.0/tcp/4001".parse::<Multiaddr>().unwrap()]);
+        assert_eq!(settings.network.transports, NetworkSettings::default().transports);
+        assert!(!settings.network.mdns);
+        assert_eq!(settings.limits.inbox_max_messages, 50);
+        assert_eq!(settings.limits.inbox_max_peer_messages, 1_000);
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_settings() {
+        assert!(Settings::parse("[network]\nlisten = [\"not an address\"]").is_err());
+        assert!(Settings::parse("[network]\ntransports = [\"udp\"]").is_err());
+        assert!(Settings::parse("[network]\nunknown = 1").is_err());
+        assert!(Settings::parse("[storage]\nsweep_interval_secs = 0").unwrap().validate().is_err());
+        assert!(Settings::parse("[limits]\nannouncement_max_ttl_secs = 0").unwrap().validate().is_err());
+        assert!(Settings::parse("[storage]\nmessage_max_age_secs = 3600").unwrap().validate().is_err());
+        assert!(Settings::parse("[storage]\nsweep_interval_secs = 9223372036854775807").unwrap().validate().is_err());
+        assert!(Settings::parse("[limits]\ndelivery_expiry_secs = 9223372036854775807").unwrap().validate().is_err());
+        assert!(Settings::parse("[network]\nidle_connection_timeout_secs = 9223372036854775807").unwrap().validate().is_err());
+        assert!(Settings::parse("[limits]\nannouncement_max_ttl_secs = -1").unwrap().validate().is_err());
+        assert!(Settings::parse("[network]\ntransports = []").unwrap().validate().is_err());
+        assert!(Settings::parse("[network]\ndial_transports = []").unwrap().validate().is_err());
+        assert!(Settings::parse("[network]\nbootstrap = [\"/ip4/127.0.0.1/tcp/4001\"]").unwrap().validate().is_err());
     }
 }
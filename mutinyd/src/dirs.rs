@@ -37,10 +37,10 @@ fn open_private_app_dir(base: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
 }
 
 pub fn open_app_data_dir() -> Result<PathBuf, Box<dyn Error>> {
-    return open_private_app_dir(user_data_dir_path()?);
+    open_private_app_dir(user_data_dir_path()?)
 }
 
 pub fn open_app_runtime_dir() -> Result<PathBuf, Box<dyn Error>> {
-    return open_private_app_dir(user_runtime_dir_path()?);
+    open_private_app_dir(user_runtime_dir_path()?)
 }
 
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::protocol::ResponseBody;
+
+/// Identifies a subscription by client connection id and request id.
+pub type SubscriberKey = (usize, usize);
+
+/// Fans out events to subscribed clients without blocking the sender.
+///
+/// Each subscriber is fed by its own task and can fall up to `capacity`
+/// events behind. A subscriber which falls further behind misses the
+/// oldest events and is sent a `Lagged` event with the number missed.
+pub struct Subscribers {
+    sender: broadcast::Sender<ResponseBody>,
+    tasks: HashMap<SubscriberKey, JoinHandle<()>>,
+}
+
+impl Subscribers {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, tasks: HashMap::new() }
+    }
+
+    pub fn send(&self, event: ResponseBody) {
+        // Only fails if there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&mut self, key: SubscriberKey, response: mpsc::Sender<ResponseBody>) {
+        let task = tokio::spawn(forward(self.sender.subscribe(), response));
+        if let Some(previous) = self.tasks.insert(key, task) {
+            previous.abort();
+        }
+    }
+
+    /// Ends all subscriptions matching `matches`, returning the number
+    /// removed.
+    pub fn remove(&mut self, matches: impl Fn(&SubscriberKey) -> bool) -> usize {
+        let before = self.tasks.len();
+        self.tasks.retain(|key, task| {
+            if matches(key) {
+                task.abort();
+                false
+            } else {
+                true
+            }
+        });
+        before - self.tasks.len()
+    }
+
+    /// Forgets subscriptions whose client has gone away.
+    pub fn prune(&mut self) {
+        self.tasks.retain(|_, task| !task.is_finished());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+async fn forward(mut receiver: broadcast::Receiver<ResponseBody>, response: mpsc::Sender<ResponseBody>) {
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => ResponseBody::Lagged { missed },
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = response.closed() => return,
+        };
+        if response.send(event).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    fn peer_event(n: usize) -> ResponseBody {
+        ResponseBody::PeerDiscovered { peer_id: n.to_string() }
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_is_told_it_lagged() {
+        let mut subscribers = Subscribers::new(2);
+        let (fast_tx, mut fast_rx) = mpsc::channel(10);
+        let (slow_tx, mut slow_rx) = mpsc::channel(1);
+        subscribers.subscribe((1, 1), fast_tx);
+        subscribers.subscribe((2, 1), slow_tx);
+        // Let the forwarding tasks start before sending anything
+        tokio::task::yield_now().await;
+
+        for n in 0..5 {
+            subscribers.send(peer_event(n));
+            tokio::task::yield_now().await;
+        }
+        for n in 0..5 {
+            assert_eq!(fast_rx.recv().await.unwrap(), peer_event(n));
+        }
+        // The slow subscriber only has room for the two most recent
+        // events once its channel is full
+        let mut received = vec![];
+        while let Ok(Some(event)) = timeout(Duration::from_millis(100), slow_rx.recv()).await {
+            received.push(event);
+        }
+        assert_eq!(received, vec![
+            peer_event(0),
+            peer_event(1),
+            ResponseBody::Lagged { missed: 1 },
+            peer_event(3),
+            peer_event(4),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn removed_subscription_ends_stream() {
+        let mut subscribers = Subscribers::new(10);
+        let (tx, mut rx) = mpsc::channel(10);
+        subscribers.subscribe((1, 1), tx);
+        assert_eq!(subscribers.remove(|(client_id, _)| *client_id == 2), 0);
+        assert_eq!(subscribers.remove(|(client_id, _)| *client_id == 1), 1);
+        assert!(subscribers.is_empty());
+        let result = timeout(Duration::from_millis(1000), rx.recv()).await.unwrap();
+        assert_eq!(result, None);
+    }
+}
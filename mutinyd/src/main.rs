@@ -7,8 +7,10 @@ mod server;
 mod dirs;
 mod config;
 mod client;
+mod events;
 mod swarm;
 mod store;
+mod signing;
 
 /// Runtime for peer-to-peer web apps
 #[derive(Parser, Debug)]
@@ -21,6 +23,24 @@ struct Args {
     /// Local peer's data directory
     #[arg(short, long)]
     data: Option<PathBuf>,
+
+    /// Config file [default: <DATA>/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Transport to listen on (may be repeated) [default: quic tcp]
+    #[arg(short, long = "transport", value_enum)]
+    transports: Vec<swarm::Transport>,
+
+    /// Address to listen on, e.g. /ip4/0.0.0.0/tcp/4001 (may be repeated,
+    /// overrides --transport)
+    #[arg(short, long = "listen")]
+    listen_addresses: Vec<libp2p::Multiaddr>,
+
+    /// Peer to join the DHT through, e.g.
+    /// /ip4/203.0.113.1/tcp/4001/p2p/<PEER_ID> (may be repeated)
+    #[arg(short, long)]
+    bootstrap: Vec<libp2p::Multiaddr>,
 }
 
 #[tokio::main]
@@ -42,6 +62,30 @@ async fn main() {
     });
     println!("Socket path: {:?}", socket_path);
 
+    let config_path = args.config.clone().unwrap_or_else(|| data_dir.join("config.toml"));
+    if args.config.is_some() && !config_path.exists() {
+        eprintln!("Error: Config file not found: {:?}", config_path);
+        process::exit(1);
+    }
+    let mut settings = config::Settings::load(&config_path).unwrap_or_else(|err| {
+        eprintln!("Error: Invalid config file {:?}: {}", config_path, err);
+        process::exit(1);
+    });
+    // Command line options take precedence over the config file
+    if !args.transports.is_empty() {
+        settings.network.transports = args.transports;
+    }
+    if !args.listen_addresses.is_empty() {
+        settings.network.listen = args.listen_addresses;
+    }
+    if !args.bootstrap.is_empty() {
+        settings.network.bootstrap = args.bootstrap;
+    }
+    if let Err(err) = settings.validate() {
+        eprintln!("Error: Invalid config: {}", err);
+        process::exit(1);
+    }
+
     let keypair_path = data_dir.join("identity.key");
     let db_path = data_dir.join("data.db");
 
@@ -67,7 +111,8 @@ async fn main() {
     let config = config::Config::load(
         keypair_path.clone(),
         socket_path.clone(),
-        db_path.clone()
+        db_path.clone(),
+        settings,
     ).unwrap();
 
     server::Server::start(config).await.unwrap();
@@ -24,13 +24,33 @@ pub enum RequestBody {
     },
     LocalPeerId,
     Peers,
+    PeerInfo {
+        peer: String,
+    },
+    ListenAddresses,
     DialAddress {
         address: String,
     },
     Announce {
         peer: String,
+        app_uuid: String,
+        /// Only shows the announcement to this app on the peer
+        to_app_uuid: Option<String>,
+        data: serde_json::Value,
+        /// Seconds until the announcement expires, capped by each
+        /// peer's `announcement_max_ttl_secs`
+        ttl: Option<u64>,
+    },
+    /// Announces an app to every peer, including ones discovered later
+    BroadcastAnnounce {
         app_uuid: String,
         data: serde_json::Value,
+        ttl: Option<u64>,
+    },
+    /// Stops broadcasting an app's announcement and asks every peer
+    /// to forget it
+    WithdrawAnnouncement {
+        app_uuid: String,
     },
     SendMessage {
         peer: String,
@@ -46,12 +66,105 @@ pub enum RequestBody {
         app_uuid: String,
         message_id: usize,
     },
-    AppAnnouncements,
+    OutboxMessages {
+        app_uuid: String,
+    },
+    DeleteOutboxMessage {
+        app_uuid: String,
+        message_id: usize,
+    },
+    GetInboxQuota {
+        app_uuid: String,
+    },
+    SetInboxQuota {
+        app_uuid: String,
+        /// Maximum number of messages in the app's inbox, or None for
+        /// the daemon default
+        max_messages: Option<u32>,
+        /// Maximum number of messages in the app's inbox from any
+        /// single remote peer, or None for the daemon default
+        max_peer_messages: Option<u32>,
+    },
+    /// Only accept messages from apps on the allow-list
+    RestrictMessages {
+        app_uuid: String,
+        restricted: bool,
+    },
+    AllowMessages {
+        app_uuid: String,
+        peer: String,
+        from_app_uuid: String,
+    },
+    DisallowMessages {
+        app_uuid: String,
+        peer: String,
+        from_app_uuid: String,
+    },
+    MessageAllowList {
+        app_uuid: String,
+    },
+    SendInvite {
+        peer: String,
+        app_uuid: String,
+        from_app_uuid: String,
+        data: serde_json::Value,
+    },
+    Invites {
+        app_uuid: String,
+    },
+    AcceptInvite {
+        app_uuid: String,
+        invite_id: usize,
+    },
+    RejectInvite {
+        app_uuid: String,
+        invite_id: usize,
+    },
+    /// Lists unexpired announcements matching the filter, optionally
+    /// only those received since a timestamp
+    AppAnnouncements {
+        peer: Option<String>,
+        app_uuid: Option<String>,
+        to_app_uuid: Option<String>,
+        since: Option<i64>,
+    },
+    /// Publishes data to subscribers of one of the app's topics
+    Publish {
+        app_uuid: String,
+        topic: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Subscribes a local app to one of its own topics, or a topic
+    /// published to by an app on its allow-list (local or remote)
+    SubscribeTopic {
+        app_uuid: String,
+        /// The publishing app's peer, defaults to the local peer
+        peer: Option<String>,
+        /// The publishing app, defaults to `app_uuid`
+        publisher_uuid: Option<String>,
+        topic: String,
+    },
+    /// Ends a subscription made by an earlier request on the same
+    /// connection
+    Cancel {
+        request_id: usize,
+    },
     SubscribePeerEvents,
-    SubscribeAnnounceEvents,
+    SubscribeAnnounceEvents {
+        peer: Option<String>,
+        app_uuid: Option<String>,
+        to_app_uuid: Option<String>,
+    },
     SubscribeInboxEvents {
         app_uuid: String,
     },
+    SubscribeOutboxEvents {
+        app_uuid: String,
+    },
+    SubscribeInviteEvents {
+        app_uuid: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -67,12 +180,41 @@ pub enum ResponseBody {
     Error {
         message: String,
     },
+    /// Sent on a subscription in place of events which were dropped
+    /// because the client was not reading them fast enough.
+    Lagged {
+        missed: u64,
+    },
     PeerDiscovered {
         peer_id: String
     },
     PeerExpired {
         peer_id: String
     },
+    PeerConnected {
+        peer_id: String,
+        /// The remote address of the connection.
+        address: String,
+        direction: ConnectionDirection,
+        transport: String,
+        /// Number of open connections to the peer, including this one.
+        connections: u32,
+    },
+    PeerDisconnected {
+        peer_id: String,
+        address: String,
+        direction: ConnectionDirection,
+        transport: String,
+        /// Number of connections to the peer still open.
+        connections: u32,
+        error: Option<String>,
+    },
+    PeerIdentified {
+        peer_id: String,
+        agent_version: String,
+        protocol_version: String,
+        protocols: Vec<String>,
+    },
     CreateAppInstance {
         uuid: String
     },
@@ -86,19 +228,107 @@ pub enum ResponseBody {
         peer_id: String
     },
     Peers {
-        peers: Vec<String>,
+        peers: Vec<PeerInfo>,
+    },
+    PeerInfo (PeerInfo),
+    ListenAddresses {
+        addresses: Vec<String>,
+    },
+    DialAddress {
+        peer_id: String,
+        /// Protocols supported by the peer, empty if it could not be
+        /// identified.
+        protocols: Vec<String>,
     },
     Message (Message),
     InboxMessages {
         messages: Vec<Message>
     },
+    SendMessage {
+        id: usize,
+    },
+    OutboxMessages {
+        messages: Vec<OutboxMessage>
+    },
+    GetInboxQuota {
+        max_messages: u32,
+        max_peer_messages: u32,
+    },
+    MessageAllowList {
+        restricted: bool,
+        allowed: Vec<MessageAllow>,
+    },
+    Invite (Invite),
+    Invites {
+        invites: Vec<Invite>,
+    },
+    InviteAccepted {
+        peer: String,
+        app_uuid: String,
+    },
+    InviteRejected {
+        peer: String,
+        app_uuid: String,
+    },
+    /// An invite sent to the app on `peer` could not be delivered
+    InviteFailed {
+        peer: String,
+        app_uuid: String,
+        error: String,
+        error_kind: DeliveryError,
+    },
+    /// A response to an invite from the app on `peer` could not be
+    /// delivered. If it was accepted, messages from that app are no
+    /// longer allowed.
+    InviteResponseFailed {
+        peer: String,
+        app_uuid: String,
+        accepted: bool,
+        error: String,
+        error_kind: DeliveryError,
+    },
+    MessageDelivered {
+        id: usize,
+        peer: String,
+        uuid: String,
+    },
+    MessageFailed {
+        id: usize,
+        peer: String,
+        uuid: String,
+        error: String,
+        error_kind: Option<DeliveryError>,
+        state: OutboxState,
+    },
     AppAnnouncements {
         announcements: Vec<AppAnnouncement>
     },
     AppAnnouncement {
         peer: String,
         app_uuid: String,
+        to_app_uuid: Option<String>,
         data: serde_json::Value,
+        received: i64,
+        expires: i64,
+        #[serde(with = "serde_bytes")]
+        signature: Option<Vec<u8>>,
+        signed_at: Option<i64>,
+        verification: Verification,
+    },
+    /// An announcement was withdrawn or expired
+    AppAnnouncementRemoved {
+        peer: String,
+        app_uuid: String,
+        to_app_uuid: Option<String>,
+    },
+    TopicMessage {
+        /// The publishing peer
+        peer: String,
+        /// The publishing app
+        app_uuid: String,
+        topic: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
     },
 }
 
@@ -110,12 +340,218 @@ pub struct Message {
     pub uuid: String,
     #[serde(with = "serde_bytes")]
     pub message: Vec<u8>,
+    /// The sending peer's signature over the message
+    #[serde(with = "serde_bytes")]
+    pub signature: Option<Vec<u8>>,
+    /// When the sending peer signed the message
+    pub signed_at: Option<i64>,
+    pub verification: Verification,
+}
+
+/// Whether content was signed by the peer it came from.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Verification {
+    /// Not signed, e.g. from an older peer. Signatures which fail
+    /// verification are dropped on receipt, so are also unsigned.
+    Unsigned,
+    /// Signed, but the peer's public key isn't known to check it, e.g.
+    /// an RSA peer which hasn't been identified.
+    Unverifiable,
+    Verified,
+}
+
+impl Verification {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verification::Unsigned => "unsigned",
+            Verification::Unverifiable => "unverifiable",
+            Verification::Verified => "verified",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag="type")]
+pub struct PeerInfo {
+    pub peer_id: String,
+    /// Whether the peer currently has a known address from mDNS, the
+    /// DHT, identify or a manual dial.
+    pub discovered: bool,
+    pub connected: bool,
+    /// All stored addresses, including ones from previous runs.
+    pub addresses: Vec<PeerAddress>,
+    pub last_seen: Option<i64>,
+    // The following are only known once the peer has been identified
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub protocols: Vec<String>,
+    /// Our address as seen by the peer.
+    pub observed_address: Option<String>,
+    /// Hex encoded protobuf encoding of the peer's public key.
+    pub public_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PeerAddress {
+    pub address: String,
+    pub source: AddressSource,
+    pub last_seen: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
+}
+
+/// How the local peer learned a remote peer's address.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum AddressSource {
+    Mdns,
+    Identify,
+    Dht,
+    Dial,
+}
+
+impl AddressSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressSource::Mdns => "mdns",
+            AddressSource::Identify => "identify",
+            AddressSource::Dht => "dht",
+            AddressSource::Dial => "dial",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum OutboxState {
+    Queued,
+    InFlight,
+    Delivered,
+    Failed,
+}
+
+/// Why a delivery attempt failed, distinguishing a peer which rejected
+/// the message from one which could not be reached.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum DeliveryError {
+    /// The peer could not be reached, or the connection failed before
+    /// it responded.
+    Unreachable,
+    /// The peer does not know the sending or receiving app.
+    UnknownApp,
+    NotAllowed,
+    QuotaExceeded,
+    /// The peer does not support the request, e.g. it is running an
+    /// incompatible version.
+    UnsupportedVersion,
+    /// The peer failed to handle the message.
+    InternalError,
+    /// The message was not delivered before it expired.
+    Expired,
+}
+
+impl DeliveryError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryError::Unreachable => "unreachable",
+            DeliveryError::UnknownApp => "unknown_app",
+            DeliveryError::NotAllowed => "not_allowed",
+            DeliveryError::QuotaExceeded => "quota_exceeded",
+            DeliveryError::UnsupportedVersion => "unsupported_version",
+            DeliveryError::InternalError => "internal_error",
+            DeliveryError::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag="type")]
+pub struct OutboxMessage {
+    pub id: usize,
+    /// Destination peer
+    pub peer: String,
+    /// Destination app instance
+    pub uuid: String,
+    #[serde(with = "serde_bytes")]
+    pub message: Vec<u8>,
+    pub queued: i64,
+    pub attempts: i64,
+    pub state: OutboxState,
+    /// Reason for the most recent failed delivery attempt
+    pub error: Option<String>,
+    pub error_kind: Option<DeliveryError>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(tag="type")]
 pub struct AppAnnouncement {
+    pub peer: String,
+    pub app_uuid: String,
+    /// The local app the announcement was sent to, if any
+    pub to_app_uuid: Option<String>,
+    pub data: serde_json::Value,
+    pub received: i64,
+    pub expires: i64,
+    /// The announcing peer's signature over the announcement
+    #[serde(with = "serde_bytes")]
+    pub signature: Option<Vec<u8>>,
+    /// When the announcing peer signed the announcement
+    pub signed_at: Option<i64>,
+    pub verification: Verification,
+}
+
+/// Selects announcements by the announcing peer and app, and the local
+/// app they were sent to. Announcements sent to a particular app only
+/// match when `to_app_uuid` names it.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+pub struct AnnouncementFilter {
+    pub peer: Option<String>,
+    pub app_uuid: Option<String>,
+    pub to_app_uuid: Option<String>,
+}
+
+impl AnnouncementFilter {
+    pub fn matches(&self, peer: &str, app_uuid: &str, to_app_uuid: Option<&str>) -> bool {
+        self.peer.as_ref().is_none_or(|p| p == peer)
+            && self.app_uuid.as_ref().is_none_or(|a| a == app_uuid)
+            && to_app_uuid.is_none_or(|to| self.to_app_uuid.as_deref() == Some(to))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag="type")]
+pub struct Invite {
+    pub id: usize,
     pub peer: String,
     pub app_uuid: String,
     pub data: serde_json::Value,
 }
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MessageAllow {
+    pub peer: String,
+    pub app_uuid: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcement_filter_matches() {
+        assert!(AnnouncementFilter::default().matches("peer", "app", None));
+        // Announcements sent to an app are only shown to that app
+        assert!(!AnnouncementFilter::default().matches("peer", "app", Some("other")));
+        let filter = AnnouncementFilter {
+            peer: Some(String::from("peer")),
+            app_uuid: None,
+            to_app_uuid: Some(String::from("me")),
+        };
+        assert!(filter.matches("peer", "app", None));
+        assert!(filter.matches("peer", "app", Some("me")));
+        assert!(!filter.matches("peer", "app", Some("other")));
+        assert!(!filter.matches("other", "app", None));
+    }
+}
@@ -1,29 +1,146 @@
 use libp2p::request_response::{InboundRequestId, OutboundRequestId, ResponseChannel};
 use tokio::net::UnixStream;
 use tokio::{signal, net::UnixListener, net::unix::SocketAddr, sync::mpsc};
-use libp2p::{mdns, swarm::SwarmEvent, futures::stream::StreamExt, core::ConnectedPoint, Multiaddr, PeerId, request_response};
+use libp2p::{gossipsub, identify, kad, mdns, identity::{Keypair, PublicKey}, swarm::{SwarmEvent, ConnectionId, dial_opts::DialOpts}, futures::stream::StreamExt, core::ConnectedPoint, Multiaddr, PeerId, request_response};
 use std::collections::{HashSet, HashMap};
 use std::error::Error;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::swarm::{self, Swarm, MutinyBehaviourEvent};
-use crate::config::Config;
-use crate::protocol::{RequestBody, ResponseBody, Message};
-use crate::client::{create_client, ClientRequest};
-use crate::store::Store;
+use crate::config::{Config, Settings};
+use crate::protocol::{RequestBody, ResponseBody, AnnouncementFilter, AppAnnouncement, Message, OutboxState, Invite, AddressSource, PeerInfo, ConnectionDirection, DeliveryError, Verification};
+use crate::client::{create_client, ClientEvent, ClientRequest};
+use crate::events::{Subscribers, SubscriberKey};
+use crate::signing::{self, SignedContent, Signature};
+use crate::store::{Store, QueuedMessage};
+
+/// How often to check for requested dials which have timed out.
+const DIAL_TIMEOUT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to delete announcements which have expired.
+const ANNOUNCEMENT_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to check for broadcast announcements due to be sent again
+/// to connected peers before they expire.
+const BROADCAST_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to check the outbox for messages due another delivery attempt.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+/// Delay before retrying a message after its first failed delivery
+/// attempt, doubled for each subsequent attempt.
+const DELIVERY_BACKOFF_INITIAL_SECS: i64 = 5;
+/// Upper limit on the delay between delivery attempts.
+const DELIVERY_BACKOFF_MAX_SECS: i64 = 60 * 60;
+/// Minimum delay between redials of an unreachable peer with queued messages.
+const REDIAL_INTERVAL_SECS: i64 = 60;
+/// Delay before accepting client connections again after an error,
+/// e.g. when out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn timestamp() -> Result<i64, Box<dyn Error>> {
+    // Can't store u64 timestamp directly in sqlite, would have to store as blob
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().try_into()?)
+}
+
+/// Returns the remote address and direction of a connection.
+fn connection_endpoint(endpoint: &ConnectedPoint) -> (&Multiaddr, ConnectionDirection) {
+    match endpoint {
+        ConnectedPoint::Dialer { address, .. } => (address, ConnectionDirection::Outbound),
+        ConnectedPoint::Listener { send_back_addr, .. } => (send_back_addr, ConnectionDirection::Inbound),
+    }
+}
+
+fn delivery_backoff(attempts: i64) -> i64 {
+    let exponent = attempts.clamp(0, 30) as u32;
+    DELIVERY_BACKOFF_INITIAL_SECS
+        .saturating_mul(1 << exponent)
+        .min(DELIVERY_BACKOFF_MAX_SECS)
+}
+
+/// An invite, or response to an invite, sent to a remote peer which
+/// hasn't been acknowledged yet.
+enum PendingInvite {
+    Invite {
+        inviter: i64,
+        invitee: i64,
+        invitee_uuid: String,
+    },
+    Response {
+        invitee: i64,
+        inviter: i64,
+        inviter_uuid: String,
+        accepted: bool,
+    },
+}
+
+/// Describes why a peer didn't acknowledge a request.
+fn response_error(response: &swarm::Response) -> Option<(DeliveryError, String)> {
+    match response {
+        swarm::Response::Acknowledge => None,
+        swarm::Response::QuotaExceeded => Some((DeliveryError::QuotaExceeded, String::from("Recipient has too many pending invites"))),
+        swarm::Response::NotAllowed => Some((DeliveryError::NotAllowed, String::from("Recipient did not expect this request"))),
+        swarm::Response::UnknownApp {app_uuid} => Some((DeliveryError::UnknownApp, format!("Recipient does not know app {app_uuid}"))),
+        swarm::Response::UnsupportedVersion => Some((DeliveryError::UnsupportedVersion, String::from("Recipient does not support this request"))),
+        swarm::Response::InternalError {message} => Some((DeliveryError::InternalError, format!("Recipient error: {message}"))),
+    }
+}
+
+/// Checks a signature received from `peer` over the content built by
+/// `content` from the signing time, dropping it if it's invalid.
+fn received_signature<'a>(
+    peer: &PeerId,
+    known_key: Option<&PublicKey>,
+    signature: Option<Vec<u8>>,
+    signed_at: Option<i64>,
+    content: impl FnOnce(i64) -> SignedContent<'a>,
+) -> Option<Signature> {
+    // Signatures without a signing time are from older peers, and don't
+    // cover it
+    let (signature, signed_at) = signature.zip(signed_at)?;
+    match signing::verify(peer, known_key, &content(signed_at), &signature) {
+        Some(verification) => Some(Signature {signature, signed_at, verification}),
+        None => {
+            println!("Discarding invalid signature from {peer}");
+            None
+        },
+    }
+}
+
+/// A DialAddress request waiting for the connection to be established
+/// and the remote peer identified.
+struct PendingDial {
+    address: Multiaddr,
+    started: tokio::time::Instant,
+    peer_id: Option<PeerId>,
+    response: mpsc::Sender<ResponseBody>,
+}
 
 pub struct Server {
     swarm: Swarm,
     listener: UnixListener,
-    peer_subscribers: HashMap<usize, mpsc::Sender<ResponseBody>>,
-    announce_subscribers: HashMap<usize, mpsc::Sender<ResponseBody>>,
-    inbox_subscribers: HashMap<i64, HashMap<usize, mpsc::Sender<ResponseBody>>>,
-    client_request_receiver: mpsc::Receiver<ClientRequest>,
-    client_request_sender: mpsc::Sender<ClientRequest>,
+    peer_subscribers: Subscribers,
+    announce_subscribers: HashMap<AnnouncementFilter, Subscribers>,
+    inbox_subscribers: HashMap<i64, Subscribers>,
+    outbox_subscribers: HashMap<i64, Subscribers>,
+    invite_subscribers: HashMap<i64, Subscribers>,
+    topic_subscribers: HashMap<gossipsub::TopicHash, Subscribers>,
+    client_request_receiver: mpsc::Receiver<ClientEvent>,
+    client_request_sender: mpsc::Sender<ClientEvent>,
+    next_client_id: usize,
     peers: HashMap<PeerId, HashSet<Multiaddr>>,
+    identified: HashMap<PeerId, identify::Info>,
+    listen_addresses: HashSet<Multiaddr>,
     peer_id: libp2p::PeerId,
+    /// Signs announcements and messages sent by local apps
+    keypair: Keypair,
     delivery_attempts: HashMap<OutboundRequestId, i64>,
+    pending_invites: HashMap<OutboundRequestId, PendingInvite>,
+    pending_dials: HashMap<ConnectionId, PendingDial>,
+    redials: HashMap<PeerId, i64>,
+    /// When each local app's broadcast was last sent to connected peers
+    broadcasts_sent: HashMap<String, i64>,
+    /// Peers sent each local app's announcement, which are told when
+    /// it is withdrawn
+    announced_to: HashMap<String, HashSet<PeerId>>,
     store: Store,
+    settings: Settings,
 }
 
 impl Server {
@@ -32,23 +149,38 @@ impl Server {
         let (tx, rx) = mpsc::channel(100);
         let mut server = Self {
             listener: UnixListener::bind(config.socket_path.as_path())?,
-            peer_subscribers: HashMap::new(),
+            peer_subscribers: Subscribers::new(config.settings.limits.event_buffer_size),
             announce_subscribers: HashMap::new(),
             inbox_subscribers: HashMap::new(),
-            swarm: swarm::start(config.keypair).await?,
+            outbox_subscribers: HashMap::new(),
+            invite_subscribers: HashMap::new(),
+            topic_subscribers: HashMap::new(),
+            swarm: swarm::start(config.keypair.clone(), &config.settings.network).await?,
             client_request_receiver: rx,
             client_request_sender: tx,
+            next_client_id: 0,
             peers: HashMap::new(),
+            identified: HashMap::new(),
+            listen_addresses: HashSet::new(),
             peer_id: libp2p::identity::PeerId::from_public_key(pubkey),
+            keypair: config.keypair,
             delivery_attempts: HashMap::new(),
+            pending_invites: HashMap::new(),
+            pending_dials: HashMap::new(),
+            redials: HashMap::new(),
+            broadcasts_sent: HashMap::new(),
+            announced_to: HashMap::new(),
             store: Store::new(config.db_connection),
+            settings: config.settings,
         };
         println!("  Local peer ID: {}", server.peer_id);
         {
             let tx = server.store.transaction()?;
             tx.migrate()?;
+            tx.set_unknown_announcement_expiry(server.settings.limits.announcement_max_ttl_secs)?;
             tx.commit()?;
         }
+        server.redial_queued_peers()?;
         server.run().await;
         println!("Removing {:?}", config.socket_path);
         tokio::fs::remove_file(config.socket_path.as_path()).await?;
@@ -56,25 +188,92 @@ impl Server {
     }
 
     async fn run(&mut self) -> () {
+        let mut delivery_interval = tokio::time::interval(DELIVERY_INTERVAL);
+        let mut dial_timeout_interval = tokio::time::interval(DIAL_TIMEOUT_INTERVAL);
+        let mut announcement_expiry_interval = tokio::time::interval(ANNOUNCEMENT_EXPIRY_INTERVAL);
+        let mut broadcast_refresh_interval = tokio::time::interval(BROADCAST_REFRESH_INTERVAL);
+        let mut bootstrap_interval = tokio::time::interval(
+            Duration::from_secs(self.settings.network.bootstrap_interval_secs)
+        );
+        let mut sweep_interval = tokio::time::interval(
+            Duration::from_secs(self.settings.storage.sweep_interval_secs)
+        );
+        // Don't compact immediately on startup, wait for the first interval to elapse
+        let compact_period = Duration::from_secs(self.settings.storage.compact_interval_secs);
+        let mut compact_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + compact_period,
+            compact_period,
+        );
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => {
-                    self.swarm_event(event).await.unwrap();
+                    if let Err(err) = self.swarm_event(event).await {
+                        eprintln!("Error handling swarm event: {}", err);
+                    }
+                },
+                _ = delivery_interval.tick() => {
+                    if let Err(err) = self.deliver_due_messages().await {
+                        eprintln!("Error delivering queued messages: {}", err);
+                    }
+                },
+                _ = dial_timeout_interval.tick() => {
+                    self.expire_dials().await;
+                },
+                _ = announcement_expiry_interval.tick() => {
+                    if let Err(err) = self.expire_announcements() {
+                        eprintln!("Error deleting expired announcements: {}", err);
+                    }
+                },
+                _ = broadcast_refresh_interval.tick() => {
+                    if let Err(err) = self.refresh_broadcasts() {
+                        eprintln!("Error refreshing broadcast announcements: {}", err);
+                    }
                 },
-                connection = self.listener.accept() => {
-                    self.spawn_client(connection.unwrap()).await;
+                _ = bootstrap_interval.tick() => {
+                    // Fails if the routing table is empty, in which case
+                    // there is nothing to refresh yet
+                    let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
                 },
-                client_request = self.client_request_receiver.recv() => {
-                    self.client_request(client_request.unwrap()).await;
+                _ = sweep_interval.tick() => {
+                    if let Err(err) = self.sweep_messages() {
+                        eprintln!("Error deleting expired messages: {}", err);
+                    }
+                    self.prune_subscribers();
+                },
+                _ = compact_interval.tick() => {
+                    println!("Compacting database");
+                    if let Err(err) = self.store.compact() {
+                        eprintln!("Error compacting database: {}", err);
+                    }
+                },
+                connection = self.listener.accept() => match connection {
+                    Ok(connection) => self.spawn_client(connection).await,
+                    Err(err) => {
+                        eprintln!("Error accepting client connection: {}", err);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    },
+                },
+                // The server holds a sender itself, so this never ends
+                Some(event) = self.client_request_receiver.recv() => {
+                    match event {
+                        ClientEvent::Request(request) => {
+                            self.client_request(request).await;
+                        },
+                        ClientEvent::Disconnected {client_id} => {
+                            self.remove_subscribers(|(id, _)| *id == client_id);
+                        },
+                    }
                 },
                 _signal = signal::ctrl_c() => break,
             }
         }
     }
 
-    async fn spawn_client(&self, connection: (UnixStream, SocketAddr)) -> () {
+    async fn spawn_client(&mut self, connection: (UnixStream, SocketAddr)) -> () {
         let (stream, _addr) = connection;
-        let client = create_client(stream, self.client_request_sender.clone());
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        let client = create_client(stream, client_id, self.client_request_sender.clone());
         tokio::spawn(client.start());
     }
 
@@ -84,7 +283,7 @@ impl Server {
                 self.swarm_request(peer_id, request_id, request, channel).await
             },
             swarm::Message::Response {request_id, response} => {
-                self.swarm_response(peer_id, request_id, response)
+                self.swarm_response(peer_id, request_id, response).await
             },
         }
     }
@@ -93,113 +292,680 @@ impl Server {
         &mut self,
         peer: libp2p::PeerId,
         _request_id: InboundRequestId,
-        request: swarm::Request,
+        request: swarm::WireRequest,
         channel: ResponseChannel<swarm::Response>,
     ) -> Result<(), Box<dyn Error>> {
-        // Can't store u64 timestamp directly in sqlite, would have to store as blob
-        let received: i64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().try_into()?;
+        let response = match request {
+            swarm::WireRequest::Supported(request) => {
+                // Requests come from untrusted peers, so a failure is reported
+                // back to the sender rather than treated as our own error
+                match self.handle_swarm_request(peer, request).await {
+                    Ok(response) => response,
+                    Err(err) => {
+                        // The error may reveal local details, e.g.
+                        // database paths, so only the log gets them
+                        eprintln!("Error handling request from {peer}: {err}");
+                        swarm::Response::InternalError {
+                            message: String::from("Failed to handle request"),
+                        }
+                    },
+                }
+            },
+            swarm::WireRequest::Unsupported(_) => {
+                println!("Rejecting unsupported request from {peer}");
+                swarm::Response::UnsupportedVersion
+            },
+        };
+        let _ = self.swarm.behaviour_mut().request_response.send_response(
+            channel,
+            response,
+        );
+        Ok(())
+    }
+
+    async fn handle_swarm_request(
+        &mut self,
+        peer: libp2p::PeerId,
+        request: swarm::Request,
+    ) -> Result<swarm::Response, Box<dyn Error>> {
+        let received = timestamp()?;
+        let peer_str = peer.to_base58();
+        // Needed for peers whose ID doesn't embed their public key
+        let known_key = self.identified.get(&peer).map(|info| info.public_key.clone());
         let tx = self.store.transaction()?;
-        let peer_id = tx.get_or_put_peer(&peer.to_base58())?;
-        match request {
-            swarm::Request::Announce { app_uuid, data } => {
-                let app = tx.get_or_put_app(peer_id, &app_uuid)?;
-                tx.set_app_announcement(app, received, &data)?;
+        let peer_id = tx.get_or_put_peer(&peer_str)?;
+        let response = match request {
+            swarm::Request::Announce { app_uuid, to_app_uuid, data, ttl, signature, signed_at } => {
+                if let Some(to_app_uuid) = to_app_uuid.as_ref() {
+                    let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                    if tx.get_app(local_peer_id, to_app_uuid)?.is_none() {
+                        return Ok(swarm::Response::UnknownApp { app_uuid: to_app_uuid.clone() });
+                    }
+                }
                 tx.commit()?;
-                self.announce_subscribers_send(ResponseBody::AppAnnouncement {
-                    peer: peer.to_base58(),
-                    app_uuid,
-                    data,
-                }).await;
+                let signature = received_signature(&peer, known_key.as_ref(), signature, signed_at, |signed_at| {
+                    SignedContent::Announce {
+                        peer: &peer_str,
+                        signed_at,
+                        app_uuid: &app_uuid,
+                        to_app_uuid: to_app_uuid.as_deref(),
+                        ttl,
+                        data: &data,
+                    }
+                });
+                self.receive_announcement(peer_str.clone(), app_uuid, to_app_uuid, data, ttl, signature)?;
+                swarm::Response::Acknowledge
+            },
+            swarm::Request::Withdraw { app_uuid } => {
+                tx.commit()?;
+                // Apps belong to the peer, so it can only withdraw its own
+                self.remove_announcement(&peer.to_base58(), app_uuid)?;
+                swarm::Response::Acknowledge
             },
             swarm::Request::Message {
                 from_app_uuid,
                 to_app_uuid,
                 message,
+                signature,
+                signed_at,
             } => {
                 let local_peer_id = tx.get_peer(&self.peer_id.to_base58())?.ok_or("Cannot find local peer ID in database")?;
-                let from = tx.get_app(peer_id, &from_app_uuid)?.ok_or("Cannot find 'from' app in database")?;
-                let to = tx.get_app(local_peer_id, &to_app_uuid)?.ok_or("Cannot find 'to' app in database")?;
-                let message_id = tx.get_or_put_message_data(&message)?;
-                let id = tx.put_message_inbox(received, from, to, message_id)?;
-                tx.commit()?;
-                self.inbox_subscribers_send(to, ResponseBody::Message ( Message {
-                    id: id.try_into()?,
-                    peer: peer.to_base58(),
-                    uuid: from_app_uuid,
-                    message,
-                })).await;
+                let Some(from) = tx.get_app(peer_id, &from_app_uuid)? else {
+                    return Ok(swarm::Response::UnknownApp { app_uuid: from_app_uuid });
+                };
+                let Some(to) = tx.get_app(local_peer_id, &to_app_uuid)? else {
+                    return Ok(swarm::Response::UnknownApp { app_uuid: to_app_uuid });
+                };
+                let (max_messages, max_peer_messages) = tx.get_inbox_quota(to)?;
+                let max_messages = max_messages.unwrap_or(self.settings.limits.inbox_max_messages);
+                let max_peer_messages = max_peer_messages.unwrap_or(self.settings.limits.inbox_max_peer_messages);
+                if !tx.is_message_allowed(from, to)? {
+                    println!("Rejecting message from {peer}: {from_app_uuid} not allowed to message {to_app_uuid}");
+                    swarm::Response::NotAllowed
+                } else if tx.count_app_inbox_messages(to)? >= max_messages.into() ||
+                    tx.count_app_inbox_messages_from_peer(to, peer_id)? >= max_peer_messages.into() {
+                    println!("Rejecting message from {peer}: inbox quota exceeded for {to_app_uuid}");
+                    swarm::Response::QuotaExceeded
+                } else {
+                    // The message is still accepted as it came directly
+                    // from the peer, but can't be passed on as signed
+                    let signature = received_signature(&peer, known_key.as_ref(), signature, signed_at, |signed_at| {
+                        SignedContent::Message {
+                            peer: &peer_str,
+                            signed_at,
+                            from_app_uuid: &from_app_uuid,
+                            to_app_uuid: &to_app_uuid,
+                            message: &message,
+                        }
+                    });
+                    let message_id = tx.get_or_put_message_data(&message)?;
+                    let id = tx.put_message_inbox(received, from, to, message_id, signature.as_ref())?;
+                    tx.commit()?;
+                    self.inbox_subscribers_send(to, ResponseBody::Message ( Message {
+                        id: id.try_into()?,
+                        peer: peer_str.clone(),
+                        uuid: from_app_uuid,
+                        message,
+                        signed_at: signature.as_ref().map(|s| s.signed_at),
+                        verification: signature.as_ref().map_or(Verification::Unsigned, |s| s.verification),
+                        signature: signature.map(|s| s.signature),
+                    }));
+                    swarm::Response::Acknowledge
+                }
+            },
+            swarm::Request::Invite {
+                from_app_uuid,
+                to_app_uuid,
+                data,
+            } => {
+                drop(tx);
+                self.receive_invite(peer, from_app_uuid, to_app_uuid, data).await?
             },
+            swarm::Request::InviteResponse {
+                from_app_uuid,
+                to_app_uuid,
+                accepted,
+            } => {
+                drop(tx);
+                self.receive_invite_response(peer, from_app_uuid, to_app_uuid, accepted).await?
+            },
+        };
+        Ok(response)
+    }
+
+    async fn receive_invite(
+        &mut self,
+        peer: PeerId,
+        from_app_uuid: String,
+        to_app_uuid: String,
+        data: serde_json::Value,
+    ) -> Result<swarm::Response, Box<dyn Error>> {
+        let received = timestamp()?;
+        let tx = self.store.transaction()?;
+        let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+        let Some(to) = tx.get_app(local_peer_id, &to_app_uuid)? else {
+            return Ok(swarm::Response::UnknownApp { app_uuid: to_app_uuid });
+        };
+        let peer_id = tx.get_or_put_peer(&peer.to_base58())?;
+        if tx.count_peer_message_invites(peer_id)? >= self.settings.limits.invite_max_peer_invites.into() {
+            println!("Rejecting invite from {peer}: too many pending invites");
+            return Ok(swarm::Response::QuotaExceeded);
         }
-        let _ = self.swarm.behaviour_mut().request_response.send_response(
-            channel,
-            swarm::Response::Acknowledge,
-        );
+        let from = tx.get_or_put_app(peer_id, &from_app_uuid)?;
+        let id = tx.put_message_invite(received, from, to, &data)?;
+        tx.commit()?;
+        self.invite_subscribers_send(to, ResponseBody::Invite(Invite {
+            id: id.try_into()?,
+            peer: peer.to_base58(),
+            app_uuid: from_app_uuid,
+            data,
+        }));
+        Ok(swarm::Response::Acknowledge)
+    }
+
+    /// Handles an invitee's response to an invite sent by a local app.
+    async fn receive_invite_response(
+        &mut self,
+        peer: PeerId,
+        invitee_uuid: String,
+        inviter_uuid: String,
+        accepted: bool,
+    ) -> Result<swarm::Response, Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+        let Some(inviter) = tx.get_app(local_peer_id, &inviter_uuid)? else {
+            return Ok(swarm::Response::UnknownApp { app_uuid: inviter_uuid });
+        };
+        let invitee = match tx.get_peer(&peer.to_base58())? {
+            Some(peer_id) => tx.get_app(peer_id, &invitee_uuid)?,
+            None => None,
+        };
+        // Ignore responses to invites that were never sent
+        let Some(invitee) = invitee else {
+            return Ok(swarm::Response::NotAllowed);
+        };
+        if !tx.take_message_invite_sent(inviter, invitee)? {
+            return Ok(swarm::Response::NotAllowed);
+        }
+        if accepted {
+            tx.put_message_allow(invitee, inviter)?;
+        }
+        tx.commit()?;
+        let peer = peer.to_base58();
+        let app_uuid = invitee_uuid;
+        self.invite_subscribers_send(inviter, if accepted {
+            ResponseBody::InviteAccepted {peer, app_uuid}
+        } else {
+            ResponseBody::InviteRejected {peer, app_uuid}
+        });
+        Ok(swarm::Response::Acknowledge)
+    }
+
+    /// Undoes the local effects of an invite or invite response which
+    /// the recipient didn't acknowledge, and tells the local app.
+    fn invite_failed(
+        &mut self,
+        peer: PeerId,
+        invite: PendingInvite,
+        kind: DeliveryError,
+        error: String,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("Invite request to {peer} failed: {error}");
+        let peer = peer.to_base58();
+        let tx = self.store.transaction()?;
+        let (app_id, event) = match invite {
+            PendingInvite::Invite {inviter, invitee, invitee_uuid} => {
+                tx.take_message_invite_sent(inviter, invitee)?;
+                (inviter, ResponseBody::InviteFailed {
+                    peer,
+                    app_uuid: invitee_uuid,
+                    error,
+                    error_kind: kind,
+                })
+            },
+            PendingInvite::Response {invitee, inviter, inviter_uuid, accepted} => {
+                // The inviter won't allow messages back, so don't
+                // leave it allowed on this side alone
+                if accepted {
+                    tx.delete_message_allow(inviter, invitee)?;
+                }
+                (invitee, ResponseBody::InviteResponseFailed {
+                    peer,
+                    app_uuid: inviter_uuid,
+                    accepted,
+                    error,
+                    error_kind: kind,
+                })
+            },
+        };
+        tx.commit()?;
+        self.invite_subscribers_send(app_id, event);
         Ok(())
     }
 
-    fn swarm_response(
+    async fn swarm_response(
         &mut self,
-        _peer: libp2p::PeerId,
+        peer: libp2p::PeerId,
         request_id: OutboundRequestId,
         response: swarm::Response,
     ) -> Result<(), Box<dyn Error>> {
-        let tx = self.store.transaction()?;
+        if let Some(invite) = self.pending_invites.remove(&request_id) {
+            if let Some((kind, error)) = response_error(&response) {
+                self.invite_failed(peer, invite, kind, error)?;
+            }
+            return Ok(());
+        }
         match response {
             swarm::Response::Acknowledge => {
                 if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
-                    tx.delete_message_outbox(outbox_id)?;
-                    // TODO: attempt to deliver next message for peer
+                    let tx = self.store.transaction()?;
+                    tx.set_message_outbox_delivered(outbox_id, timestamp()?)?;
+                    tx.commit()?;
+                    self.outbox_event(outbox_id).await?;
                 }
-            }
+            },
+            swarm::Response::QuotaExceeded => {
+                if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+                    // Leave queued, the recipient may free up space before the next attempt
+                    println!("Outbox message {outbox_id} rejected by {peer}: inbox quota exceeded");
+                    let tx = self.store.transaction()?;
+                    tx.record_outbox_error(outbox_id, DeliveryError::QuotaExceeded, "Recipient inbox quota exceeded")?;
+                    tx.commit()?;
+                    self.outbox_event(outbox_id).await?;
+                }
+                // Don't immediately retry other messages for this peer
+                return Ok(());
+            },
+            swarm::Response::NotAllowed => {
+                if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+                    println!("Outbox message {outbox_id} rejected by {peer}: not allowed");
+                    let error = "Recipient does not allow messages from this app";
+                    self.reject_message(outbox_id, DeliveryError::NotAllowed, error).await?;
+                }
+            },
+            swarm::Response::UnknownApp {app_uuid} => {
+                if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+                    println!("Outbox message {outbox_id} rejected by {peer}: unknown app {app_uuid}");
+                    let error = format!("Recipient does not know app {app_uuid}");
+                    let recipient = {
+                        let tx = self.store.transaction()?;
+                        tx.get_outbox_message(outbox_id)?.map(|(_, message)| message.uuid)
+                    };
+                    if recipient.is_some_and(|uuid| uuid == app_uuid) {
+                        self.reject_message(outbox_id, DeliveryError::UnknownApp, &error).await?;
+                    } else {
+                        // The recipient may learn about the sending app
+                        // later, e.g. from an announcement
+                        self.delivery_failed(peer, outbox_id, DeliveryError::UnknownApp, &error).await?;
+                    }
+                }
+                return Ok(());
+            },
+            swarm::Response::UnsupportedVersion => {
+                if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+                    println!("Outbox message {outbox_id} rejected by {peer}: unsupported version");
+                    let error = "Recipient does not support this message";
+                    self.reject_message(outbox_id, DeliveryError::UnsupportedVersion, error).await?;
+                }
+            },
+            swarm::Response::InternalError {message} => {
+                if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+                    println!("Outbox message {outbox_id} rejected by {peer}: {message}");
+                    let error = format!("Recipient error: {message}");
+                    self.delivery_failed(peer, outbox_id, DeliveryError::InternalError, &error).await?;
+                }
+                return Ok(());
+            },
         }
-        Ok(tx.commit()?)
+        // Peer is evidently reachable, attempt delivery of anything
+        // else still queued for it.
+        self.deliver_peer_messages(peer).await
     }
 
-    async fn peer_subscribers_send(&mut self, message: ResponseBody) -> () {
-        let mut to_remove: Vec<usize> = vec![];
-        for (request_id, sender) in &self.peer_subscribers {
-            if let Err(err) = sender.send(message.clone()).await {
-                eprintln!("Error sending message to peer event subscriber: {}", err);
-                // Remove this subscriber
-                to_remove.push(*request_id);
+    async fn swarm_outbound_failure(
+        &mut self,
+        peer: libp2p::PeerId,
+        request_id: OutboundRequestId,
+        error: request_response::OutboundFailure,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("Outbound request to {peer} failed: {error}");
+        if let Some(invite) = self.pending_invites.remove(&request_id) {
+            let kind = match error {
+                request_response::OutboundFailure::UnsupportedProtocols => DeliveryError::UnsupportedVersion,
+                _ => DeliveryError::Unreachable,
+            };
+            return self.invite_failed(peer, invite, kind, error.to_string());
+        }
+        if let Some(outbox_id) = self.delivery_attempts.remove(&request_id) {
+            if let request_response::OutboundFailure::UnsupportedProtocols = error {
+                let error = "Recipient does not support the messaging protocol";
+                self.reject_message(outbox_id, DeliveryError::UnsupportedVersion, error).await?;
+            } else {
+                self.delivery_failed(peer, outbox_id, DeliveryError::Unreachable, &error.to_string()).await?;
             }
         }
-        for request_id in to_remove {
-            self.peer_subscribers.remove(&request_id);
+        Ok(())
+    }
+
+    /// Records a failed delivery attempt, leaving the message queued
+    /// for a retry unless it has run out of attempts.
+    async fn delivery_failed(
+        &mut self,
+        peer: PeerId,
+        outbox_id: i64,
+        kind: DeliveryError,
+        error: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        let attempts = tx.record_outbox_error(outbox_id, kind, error)?;
+        // No point waiting for the next retry if this was the last permitted attempt
+        if attempts.is_some_and(|attempts| attempts >= self.settings.limits.delivery_max_attempts.into()) {
+            println!("Giving up delivery of outbox message {} to {}", outbox_id, peer);
+            tx.fail_message_outbox(outbox_id, timestamp()?)?;
         }
+        tx.commit()?;
+        self.outbox_event(outbox_id).await
+    }
+
+    /// Marks a message as failed without further delivery attempts.
+    async fn reject_message(&mut self, outbox_id: i64, kind: DeliveryError, error: &str) -> Result<(), Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        tx.record_outbox_error(outbox_id, kind, error)?;
+        tx.fail_message_outbox(outbox_id, timestamp()?)?;
+        tx.commit()?;
+        self.outbox_event(outbox_id).await
+    }
+
+    /// Notifies subscribers of the sending app about the current
+    /// delivery state of an outbox message.
+    async fn outbox_event(&mut self, outbox_id: i64) -> Result<(), Box<dyn Error>> {
+        let Some((app_id, message)) = ({
+            let tx = self.store.transaction()?;
+            tx.get_outbox_message(outbox_id)?
+        }) else {
+            // Message has been deleted since delivery was attempted
+            return Ok(());
+        };
+        let body = match message.state {
+            OutboxState::Delivered => ResponseBody::MessageDelivered {
+                id: message.id,
+                peer: message.peer,
+                uuid: message.uuid,
+            },
+            state => ResponseBody::MessageFailed {
+                id: message.id,
+                peer: message.peer,
+                uuid: message.uuid,
+                error: message.error.unwrap_or_default(),
+                error_kind: message.error_kind,
+                state,
+            },
+        };
+        self.outbox_subscribers_send(app_id, body);
+        Ok(())
     }
 
-    async fn announce_subscribers_send(&mut self, message: ResponseBody) -> () {
-        let mut to_remove: Vec<usize> = vec![];
-        for (request_id, sender) in &self.announce_subscribers {
-            if let Err(err) = sender.send(message.clone()).await {
-                eprintln!("Error sending message to announce event subscriber: {}", err);
-                // Remove this subscriber
-                to_remove.push(*request_id);
+    /// Attempts delivery of each message which is not already in
+    /// flight and whose destination peer is currently reachable,
+    /// marking messages which have exceeded the delivery policy as
+    /// failed.
+    async fn deliver_messages(&mut self, messages: Vec<QueuedMessage>) -> Result<(), Box<dyn Error>> {
+        let now = timestamp()?;
+        let tx = self.store.transaction()?;
+        let mut failed = Vec::new();
+        for entry in messages {
+            let expired = entry.expires.is_some_and(|expires| expires <= now);
+            if expired || entry.attempts >= self.settings.limits.delivery_max_attempts.into() {
+                println!("Giving up delivery of outbox message {} to {}", entry.id, entry.peer);
+                if expired {
+                    tx.record_outbox_error(entry.id, DeliveryError::Expired, "Message expired before delivery")?;
+                }
+                tx.fail_message_outbox(entry.id, now)?;
+                failed.push(entry.id);
+                continue;
             }
+            let peer: PeerId = entry.peer.parse()?;
+            let request_response = &mut self.swarm.behaviour_mut().request_response;
+            let in_flight = self.delivery_attempts.values().any(|outbox_id| *outbox_id == entry.id);
+            let reachable = self.peers.contains_key(&peer) || request_response.is_connected(&peer);
+            if in_flight {
+                continue;
+            }
+            if !reachable {
+                // Try the peer's last known addresses, delivery resumes
+                // once the connection is established
+                let redialed = self.redials.get(&peer)
+                    .is_some_and(|last| now - last < REDIAL_INTERVAL_SECS);
+                if !redialed {
+                    let since = now - self.settings.storage.peer_address_max_age_secs;
+                    let addresses: Vec<Multiaddr> = tx.list_peer_addresses(&entry.peer, since)?
+                        .into_iter()
+                        .filter_map(|addr| addr.parse().ok())
+                        .collect();
+                    if !addresses.is_empty() {
+                        self.redials.insert(peer, now);
+                        swarm::dial_peer(&mut self.swarm, peer, addresses, &self.settings.network.dial_transports);
+                    }
+                }
+                continue;
+            }
+            if !request_response.is_connected(&peer) {
+                // Dial in order of transport preference, the request
+                // waits for this connection rather than dialing again
+                let addresses: Vec<Multiaddr> = self.peers.get(&peer)
+                    .map(|addrs| addrs.iter().cloned().collect())
+                    .unwrap_or_default();
+                swarm::dial_peer(&mut self.swarm, peer, addresses, &self.settings.network.dial_transports);
+            }
+            let signature = signing::sign(&self.keypair, &SignedContent::Message {
+                peer: &self.peer_id.to_base58(),
+                signed_at: now,
+                from_app_uuid: &entry.from_app_uuid,
+                to_app_uuid: &entry.to_app_uuid,
+                message: &entry.message,
+            })?;
+            let request_id = self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::Message {
+                from_app_uuid: entry.from_app_uuid,
+                to_app_uuid: entry.to_app_uuid,
+                message: entry.message,
+                signature: Some(signature),
+                signed_at: Some(now),
+            }.into());
+            self.delivery_attempts.insert(request_id, entry.id);
+            tx.record_outbox_attempt(entry.id, now + delivery_backoff(entry.attempts))?;
+        }
+        tx.commit()?;
+        for outbox_id in failed {
+            self.outbox_event(outbox_id).await?;
         }
-        for request_id in to_remove {
-            self.announce_subscribers.remove(&request_id);
+        Ok(())
+    }
+
+    /// Deletes inbox and outbox messages older than the configured maximum age.
+    fn sweep_messages(&mut self) -> Result<(), Box<dyn Error>> {
+        let max_age = self.settings.storage.message_max_age_secs;
+        let cutoff = timestamp()? - max_age;
+        let tx = self.store.transaction()?;
+        let inbox = tx.delete_inbox_messages_before(cutoff)?;
+        let outbox = tx.delete_outbox_messages_before(cutoff)?;
+        tx.delete_peer_addresses_before(timestamp()? - self.settings.storage.peer_address_max_age_secs)?;
+        tx.commit()?;
+        if inbox > 0 || outbox > 0 {
+            println!("Deleted {inbox} inbox and {outbox} outbox messages older than {max_age}s");
+        }
+        Ok(())
+    }
+
+    async fn deliver_due_messages(&mut self) -> Result<(), Box<dyn Error>> {
+        let messages = {
+            let tx = self.store.transaction()?;
+            tx.list_due_outbox_messages(timestamp()?)?
+        };
+        self.deliver_messages(messages).await
+    }
+
+    /// Attempts delivery of all queued messages for a peer, ignoring
+    /// any backoff. Used when a peer is discovered or reconnects.
+    async fn deliver_peer_messages(&mut self, peer: PeerId) -> Result<(), Box<dyn Error>> {
+        let messages = {
+            let tx = self.store.transaction()?;
+            tx.list_peer_outbox_messages(&peer.to_base58())?
+        };
+        self.deliver_messages(messages).await
+    }
+
+    fn peer_subscribers_send(&self, message: ResponseBody) {
+        self.peer_subscribers.send(message);
+    }
+
+    /// Sends an announcement event to subscribers whose filter it
+    /// matches.
+    fn announce_subscribers_send(&self, message: ResponseBody) {
+        let (peer, app_uuid, to_app_uuid) = match &message {
+            ResponseBody::AppAnnouncement {peer, app_uuid, to_app_uuid, ..} |
+            ResponseBody::AppAnnouncementRemoved {peer, app_uuid, to_app_uuid} => {
+                (peer, app_uuid, to_app_uuid.as_deref())
+            },
+            _ => return,
+        };
+        for (filter, subscribers) in &self.announce_subscribers {
+            if filter.matches(peer, app_uuid, to_app_uuid) {
+                subscribers.send(message.clone());
+            }
         }
     }
 
-    async fn inbox_subscribers_send(&mut self, app_id: i64, message: ResponseBody) -> () {
-        let mut to_remove: Vec<usize> = vec![] ;
+    fn inbox_subscribers_send(&self, app_id: i64, message: ResponseBody) {
         if let Some(subscribers) = self.inbox_subscribers.get(&app_id) {
-            for (request_id, sender) in subscribers {
-                if let Err(err) = sender.send(message.clone()).await {
-                    eprintln!("Error sending message to announce event subscriber: {}", err);
-                    // Remove this subscriber
-                    to_remove.push(*request_id);
+            subscribers.send(message);
+        }
+    }
+
+    fn outbox_subscribers_send(&self, app_id: i64, message: ResponseBody) {
+        if let Some(subscribers) = self.outbox_subscribers.get(&app_id) {
+            subscribers.send(message);
+        }
+    }
+
+    fn invite_subscribers_send(&self, app_id: i64, message: ResponseBody) {
+        if let Some(subscribers) = self.invite_subscribers.get(&app_id) {
+            subscribers.send(message);
+        }
+    }
+
+    fn topic_subscribers_send(&self, topic: &gossipsub::TopicHash, message: ResponseBody) {
+        if let Some(subscribers) = self.topic_subscribers.get(topic) {
+            subscribers.send(message);
+        }
+    }
+
+    /// Leaves gossipsub topics which no longer have any subscribers.
+    fn unsubscribe_topics(&mut self) {
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        self.topic_subscribers.retain(|topic, subscribers| {
+            if subscribers.is_empty() {
+                if let Err(err) = gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.as_str())) {
+                    eprintln!("Failed to unsubscribe from topic {topic}: {err}");
                 }
+                false
+            } else {
+                true
             }
-            for request_id in to_remove {
-                self.announce_subscribers.remove(&request_id);
+        });
+    }
+
+    /// Ends every subscription matching `matches`, returning the number
+    /// removed.
+    fn remove_subscribers(&mut self, matches: impl Fn(&SubscriberKey) -> bool) -> usize {
+        let mut removed = self.peer_subscribers.remove(&matches);
+        self.announce_subscribers.retain(|_, subscribers| {
+            removed += subscribers.remove(&matches);
+            !subscribers.is_empty()
+        });
+        for app_subscribers in [
+            &mut self.inbox_subscribers,
+            &mut self.outbox_subscribers,
+            &mut self.invite_subscribers,
+        ] {
+            app_subscribers.retain(|_, subscribers| {
+                removed += subscribers.remove(&matches);
+                !subscribers.is_empty()
+            });
+        }
+        for subscribers in self.topic_subscribers.values_mut() {
+            removed += subscribers.remove(&matches);
+        }
+        self.unsubscribe_topics();
+        removed
+    }
+
+    /// Forgets subscriptions whose client has gone away without the
+    /// server being told, e.g. a request which arrived after the
+    /// client disconnected.
+    fn prune_subscribers(&mut self) {
+        self.peer_subscribers.prune();
+        self.announce_subscribers.retain(|_, subscribers| {
+            subscribers.prune();
+            !subscribers.is_empty()
+        });
+        for app_subscribers in [
+            &mut self.inbox_subscribers,
+            &mut self.outbox_subscribers,
+            &mut self.invite_subscribers,
+        ] {
+            app_subscribers.retain(|_, subscribers| {
+                subscribers.prune();
+                !subscribers.is_empty()
+            });
+        }
+        for subscribers in self.topic_subscribers.values_mut() {
+            subscribers.prune();
+        }
+        self.unsubscribe_topics();
+    }
+
+    fn store_peer_address(&mut self, peer_id: PeerId, addr: &Multiaddr, source: AddressSource) -> Result<(), Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        let peer = tx.get_or_put_peer(&peer_id.to_base58())?;
+        tx.put_peer_address(peer, &addr.to_string(), source, timestamp()?)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Dials recently seen peers with queued outbox messages at the
+    /// addresses stored by previous runs. Delivery resumes once they
+    /// connect, they aren't treated as discovered until then.
+    fn redial_queued_peers(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = timestamp()?;
+        let since = now - self.settings.storage.peer_address_max_age_secs;
+        let stored = {
+            let tx = self.store.transaction()?;
+            tx.list_queued_peer_addresses(since)?
+        };
+        let mut peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+        for (peer, addr) in stored {
+            let (Ok(peer_id), Ok(addr)) = (peer.parse::<PeerId>(), addr.parse::<Multiaddr>()) else {
+                continue;
+            };
+            if peer_id != self.peer_id {
+                peers.entry(peer_id).or_default().push(addr);
             }
         }
+        println!("  Redialing {} peers with queued messages", peers.len());
+        for (peer_id, addresses) in peers {
+            self.redials.insert(peer_id, now);
+            swarm::dial_peer(&mut self.swarm, peer_id, addresses, &self.settings.network.dial_transports);
+        }
+        Ok(())
     }
 
-    async fn add_peer_address(&mut self, peer_id: PeerId, addr: Multiaddr) {
+    async fn add_peer_address(&mut self, peer_id: PeerId, addr: Multiaddr, source: AddressSource) {
+        let Some(addr) = swarm::normalize_address(addr) else {
+            return;
+        };
+        if let Err(err) = self.store_peer_address(peer_id, &addr, source) {
+            eprintln!("Error storing address of {}: {}", peer_id, err);
+        }
         match self.peers.entry(peer_id) {
             std::collections::hash_map::Entry::Occupied(entry) => {
                 entry.into_mut().insert(addr);
@@ -210,96 +976,302 @@ impl Server {
                 entry.insert(addrs);
                 self.peer_subscribers_send(ResponseBody::PeerDiscovered {
                     peer_id: peer_id.to_base58(),
-                }).await;
+                });
+                if let Err(err) = self.deliver_peer_messages(peer_id).await {
+                    eprintln!("Error delivering queued messages to {}: {}", peer_id, err);
+                }
+                // Peers on the local network are few enough to connect to
+                // all of them, others get broadcasts if we ever connect
+                if source == AddressSource::Mdns {
+                    match self.has_broadcasts() {
+                        Ok(true) => self.dial_for_broadcasts(peer_id),
+                        Ok(false) => {},
+                        Err(err) => eprintln!("Error reading broadcast announcements: {}", err),
+                    }
+                }
             },
         }
     }
 
     async fn remove_peer_address(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let Some(addr) = swarm::normalize_address(addr) else {
+            return;
+        };
         let mut expired = false;
         if let Some(addrs) = self.peers.get_mut(&peer_id) {
             addrs.remove(&addr);
-            expired = addrs.len() == 0;
+            expired = addrs.is_empty();
         }
         if expired {
             self.peers.remove(&peer_id);
             self.peer_subscribers_send(ResponseBody::PeerExpired {
                 peer_id: peer_id.to_base58(),
-            }).await;
+            });
+        }
+    }
+
+    async fn finish_dial(&mut self, connection_id: ConnectionId, result: Result<Vec<String>, String>) {
+        let Some(dial) = self.pending_dials.remove(&connection_id) else {
+            return;
+        };
+        let body = match (dial.peer_id, result) {
+            (Some(peer_id), Ok(protocols)) => ResponseBody::DialAddress {
+                peer_id: peer_id.to_base58(),
+                protocols,
+            },
+            // Connected but couldn't identify, still report the peer
+            (Some(peer_id), Err(_)) => ResponseBody::DialAddress {
+                peer_id: peer_id.to_base58(),
+                protocols: Vec::new(),
+            },
+            (None, Ok(_)) => ResponseBody::Error {
+                message: format!("Failed to dial {}: not connected", dial.address),
+            },
+            (None, Err(message)) => ResponseBody::Error {
+                message: format!("Failed to dial {}: {}", dial.address, message),
+            },
+        };
+        // Ignore response failures, the client might be gone
+        let _ = dial.response.send(body).await;
+    }
+
+    /// Responds to dials for a peer which are waiting on identify.
+    async fn finish_peer_dials(&mut self, peer_id: PeerId, result: Result<Vec<String>, String>) {
+        let connection_ids: Vec<ConnectionId> = self.pending_dials.iter()
+            .filter(|(_, dial)| dial.peer_id == Some(peer_id))
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        for connection_id in connection_ids {
+            self.finish_dial(connection_id, result.clone()).await;
+        }
+    }
+
+    async fn expire_dials(&mut self) {
+        let timeout = Duration::from_secs(self.settings.network.dial_timeout_secs);
+        let expired: Vec<ConnectionId> = self.pending_dials.iter()
+            .filter(|(_, dial)| dial.started.elapsed() >= timeout)
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        for connection_id in expired {
+            self.finish_dial(connection_id, Err(String::from("Timed out"))).await;
         }
     }
 
+    fn peer_info(&mut self, peer_id: PeerId) -> Result<PeerInfo, Box<dyn Error>> {
+        let addresses = {
+            let tx = self.store.transaction()?;
+            tx.list_peer_address_info(&peer_id.to_base58())?
+        };
+        let identified = self.identified.get(&peer_id);
+        Ok(PeerInfo {
+            peer_id: peer_id.to_base58(),
+            discovered: self.peers.contains_key(&peer_id),
+            connected: self.swarm.is_connected(&peer_id),
+            last_seen: addresses.iter().map(|addr| addr.last_seen).max(),
+            addresses,
+            agent_version: identified.map(|info| info.agent_version.clone()),
+            protocol_version: identified.map(|info| info.protocol_version.clone()),
+            protocols: identified
+                .map(|info| info.protocols.iter().map(|p| p.to_string()).collect())
+                .unwrap_or_default(),
+            observed_address: identified.map(|info| info.observed_addr.to_string()),
+            public_key: identified.map(|info| {
+                info.public_key.encode_protobuf().iter().map(|b| format!("{b:02x}")).collect()
+            }),
+        })
+    }
+
     async fn swarm_event(&mut self, event: SwarmEvent<MutinyBehaviourEvent>) -> Result<(), Box<dyn Error>> {
+        let verbose = self.settings.logging.verbose;
         match event {
-            SwarmEvent::Behaviour(swarm::MutinyBehaviourEvent::Mdns(ev)) => match ev {
+            SwarmEvent::Behaviour(
+                swarm::MutinyBehaviourEvent::Mdns(ev) | swarm::MutinyBehaviourEvent::MdnsIpv6(ev)
+            ) => match ev {
                 mdns::Event::Discovered(list) => {
                     for (peer_id, addr) in list {
-                        println!("mDNS discovered a new peer: {peer_id}");
-                        self.add_peer_address(peer_id, addr).await
+                        if verbose {
+                            println!("mDNS discovered a new peer: {peer_id}");
+                        }
+                        self.add_peer_address(peer_id, addr, AddressSource::Mdns).await
                     }
                 },
                 mdns::Event::Expired(list) => {
                     for (peer_id, addr) in list {
-                        println!("mDNS discover peer has expired: {peer_id}");
+                        if verbose {
+                            println!("mDNS discover peer has expired: {peer_id}");
+                        }
                         self.remove_peer_address(peer_id, addr).await;
                     }
                 },
             },
+            SwarmEvent::Behaviour(swarm::MutinyBehaviourEvent::Kademlia(ev)) => match ev {
+                kad::Event::RoutingUpdated { peer, addresses, .. } => {
+                    if verbose {
+                        println!("DHT routing updated for peer: {peer}");
+                    }
+                    // Evicted peers may still be reachable (e.g. via mDNS),
+                    // so they're left to expire with their connections
+                    for addr in addresses.into_vec() {
+                        self.add_peer_address(peer, addr, AddressSource::Dht).await;
+                    }
+                },
+                kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::Bootstrap(Err(err)), ..
+                } => {
+                    eprintln!("DHT bootstrap failed: {err}");
+                },
+                _ => {},
+            },
             SwarmEvent::Behaviour(swarm::MutinyBehaviourEvent::RequestResponse(ev)) => match ev {
                 request_response::Event::Message {peer, message} => {
                     self.swarm_message(peer, message).await?;
                 },
-                _ => {},
-                // request_response::Event::OutboundFailure {peer, request_id, error} => {
-                // },
-                // request_response::Event::InboundFailure {peer, request_id, error} => {
-                // },
-                // request_response::Event::ResponseSent {peer, request_id} => {
-                // },
+                request_response::Event::OutboundFailure {peer, request_id, error} => {
+                    self.swarm_outbound_failure(peer, request_id, error).await?;
+                },
+                request_response::Event::InboundFailure {peer, error, ..} => {
+                    println!("Inbound request from {peer} failed: {error}");
+                },
+                request_response::Event::ResponseSent {..} => {},
             },
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("New listener: {address}");
+                self.listen_addresses.insert(address);
             },
             SwarmEvent::ExpiredListenAddr { address, .. } => {
                 println!("Expired listener: {address}");
+                self.listen_addresses.remove(&address);
             },
-            SwarmEvent::ConnectionEstablished { endpoint, .. } => {
-                if let ConnectedPoint::Dialer { address, .. } = endpoint {
-                    println!("Connection established: {address}");
+            SwarmEvent::ListenerClosed { addresses, reason, .. } => {
+                if let Err(err) = reason {
+                    eprintln!("Listener closed {addresses:?}: {err}");
+                }
+                for address in addresses {
+                    self.listen_addresses.remove(&address);
+                }
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    if verbose {
+                        println!("Connection established: {address}");
+                    }
+                }
+                let (address, direction) = connection_endpoint(&endpoint);
+                self.peer_subscribers_send(ResponseBody::PeerConnected {
+                    peer_id: peer_id.to_base58(),
+                    address: address.to_string(),
+                    direction,
+                    transport: swarm::Transport::of(address).map_or(String::from("unknown"), |t| t.to_string()),
+                    connections: num_established.get(),
+                });
+                self.redials.remove(&peer_id);
+                if let Some(dial) = self.pending_dials.get_mut(&connection_id) {
+                    // Respond once identify completes
+                    dial.peer_id = Some(peer_id);
+                    let address = dial.address.clone();
+                    self.add_peer_address(peer_id, address, AddressSource::Dial).await;
+                }
+                if let Err(err) = self.deliver_peer_messages(peer_id).await {
+                    eprintln!("Error delivering queued messages to {}: {}", peer_id, err);
+                }
+                if num_established.get() == 1 {
+                    if let Err(err) = self.push_broadcasts(peer_id) {
+                        eprintln!("Error sending broadcast announcements to {}: {}", peer_id, err);
+                    }
                 }
             },
-            SwarmEvent::ConnectionClosed { endpoint, .. } => {
-                if let ConnectedPoint::Dialer { address, .. } = endpoint {
-                    println!("Connection closed: {address}");
+            SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint, num_established, cause, .. } => {
+                self.finish_dial(connection_id, Err(String::from("Connection closed"))).await;
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    if verbose {
+                        println!("Connection closed: {address}");
+                    }
                 }
+                let (address, direction) = connection_endpoint(&endpoint);
+                self.peer_subscribers_send(ResponseBody::PeerDisconnected {
+                    peer_id: peer_id.to_base58(),
+                    address: address.to_string(),
+                    direction,
+                    transport: swarm::Transport::of(address).map_or(String::from("unknown"), |t| t.to_string()),
+                    connections: num_established,
+                    error: cause.map(|err| err.to_string()),
+                });
             },
-            SwarmEvent::Dialing {..} => {
+            SwarmEvent::Dialing {..} if verbose => {
                 println!("Dialing...");
             },
-            SwarmEvent::OutgoingConnectionError { error, .. } => {
+            SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
                 println!("Outgoing connection error: {error}");
+                self.finish_dial(connection_id, Err(error.to_string())).await;
             },
             SwarmEvent::Behaviour(swarm::MutinyBehaviourEvent::Identify(ev)) => match ev {
                 // Identification information of the local node has been sent to a peer in response to an identification request.
                 libp2p::identify::Event::Sent { peer_id, .. } => {
-                    println!("Sent identify info to {peer_id:?}")
+                    if verbose {
+                        println!("Sent identify info to {peer_id:?}")
+                    }
                 },
                 // Identification information has been received from a peer.
                 libp2p::identify::Event::Received { info, .. } => {
-                    println!("Received identify info {info:?}");
+                    if verbose {
+                        println!("Received identify info {info:?}");
+                    }
                     let peer_id = libp2p::identity::PeerId::from_public_key(&info.public_key);
+                    let kademlia = info.protocols.contains(&swarm::KADEMLIA_PROTOCOL);
+                    self.identified.insert(peer_id, info.clone());
+                    let protocols: Vec<String> = info.protocols.iter().map(|p| p.to_string()).collect();
+                    self.finish_peer_dials(peer_id, Ok(protocols.clone())).await;
+                    self.peer_subscribers_send(ResponseBody::PeerIdentified {
+                        peer_id: peer_id.to_base58(),
+                        agent_version: info.agent_version.clone(),
+                        protocol_version: info.protocol_version.clone(),
+                        protocols,
+                    });
                     for addr in info.listen_addrs {
-                        self.add_peer_address(peer_id, addr).await
+                        if let Some(addr) = swarm::normalize_address(addr.clone()).filter(|_| kademlia) {
+                            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                        }
+                        self.add_peer_address(peer_id, addr, AddressSource::Identify).await
                     }
                 },
                 // Identification information of the local node has been actively pushed to a peer.
                 libp2p::identify::Event::Pushed { peer_id, .. } => {
-                    println!("Pushed identify info to {peer_id:?}")
+                    if verbose {
+                        println!("Pushed identify info to {peer_id:?}")
+                    }
                 },
                 // Error while attempting to identify the remote.
-                libp2p::identify::Event::Error { peer_id, .. } => {
-                    println!("Error identifying remote {peer_id:?}")
+                libp2p::identify::Event::Error { peer_id, error } => {
+                    println!("Error identifying remote {peer_id:?}");
+                    self.finish_peer_dials(peer_id, Err(error.to_string())).await;
+                },
+            },
+            SwarmEvent::Behaviour(swarm::MutinyBehaviourEvent::Gossipsub(ev)) => match ev {
+                gossipsub::Event::Message { message, .. } => {
+                    let Some((topic_peer, app_uuid, topic)) = swarm::parse_app_topic(&message.topic) else {
+                        return Ok(());
+                    };
+                    // Messages are signed, so always have a source
+                    let Some(peer) = message.source else {
+                        return Ok(());
+                    };
+                    // Only the app's own peer may publish to its topics
+                    if peer.to_base58() != topic_peer {
+                        println!("Dropping message from {peer} published to {}", message.topic);
+                        return Ok(());
+                    }
+                    let event = ResponseBody::TopicMessage {
+                        peer: peer.to_base58(),
+                        app_uuid: app_uuid.to_string(),
+                        topic: topic.to_string(),
+                        data: message.data,
+                    };
+                    self.topic_subscribers_send(&message.topic, event);
+                },
+                gossipsub::Event::GossipsubNotSupported { peer_id } if verbose => {
+                    println!("Peer {peer_id} does not support gossipsub");
                 },
+                _ => {},
             },
             _ => {}
         };
@@ -334,32 +1306,373 @@ impl Server {
         Ok(None)
     }
 
-    fn send_announce(&mut self, to_peer: &str, app_uuid: String, data: serde_json::Value) -> Result<(), Box<dyn Error>> {
+    fn send_announce(
+        &mut self,
+        to_peer: &str,
+        app_uuid: String,
+        to_app_uuid: Option<String>,
+        data: serde_json::Value,
+        ttl: Option<u64>,
+    ) -> Result<(), Box<dyn Error>> {
         let peer: PeerId = to_peer.parse()?;
-        self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::Announce {
+        self.announced_to.entry(app_uuid.clone()).or_default().insert(peer);
+        let request = self.announce_request(app_uuid, to_app_uuid, data, ttl)?;
+        self.swarm.behaviour_mut().request_response.send_request(&peer, request.into());
+        Ok(())
+    }
+
+    fn sign_announcement(
+        &self,
+        app_uuid: &str,
+        to_app_uuid: Option<&str>,
+        ttl: Option<u64>,
+        data: &serde_json::Value,
+    ) -> Result<Signature, Box<dyn Error>> {
+        let signed_at = timestamp()?;
+        let signature = signing::sign(&self.keypair, &SignedContent::Announce {
+            peer: &self.peer_id.to_base58(),
+            signed_at,
+            app_uuid,
+            to_app_uuid,
+            ttl,
+            data,
+        })?;
+        Ok(Signature {signature, signed_at, verification: Verification::Verified})
+    }
+
+    /// Builds a signed announcement from a local app.
+    fn announce_request(
+        &self,
+        app_uuid: String,
+        to_app_uuid: Option<String>,
+        data: serde_json::Value,
+        ttl: Option<u64>,
+    ) -> Result<swarm::Request, Box<dyn Error>> {
+        let signature = self.sign_announcement(&app_uuid, to_app_uuid.as_deref(), ttl, &data)?;
+        Ok(swarm::Request::Announce {
+            app_uuid,
+            to_app_uuid,
+            data,
+            ttl,
+            signature: Some(signature.signature),
+            signed_at: Some(signature.signed_at),
+        })
+    }
+
+    /// Stores an announcement from an app on `peer`, which may be the
+    /// local peer, and tells subscribers about it. The signature must
+    /// already be checked.
+    fn receive_announcement(
+        &mut self,
+        peer: String,
+        app_uuid: String,
+        to_app_uuid: Option<String>,
+        data: serde_json::Value,
+        ttl: Option<u64>,
+        signature: Option<Signature>,
+    ) -> Result<(), Box<dyn Error>> {
+        let max_ttl = self.settings.limits.announcement_max_ttl_secs;
+        let ttl = ttl.map_or(max_ttl, |ttl| i64::try_from(ttl).unwrap_or(i64::MAX).min(max_ttl));
+        let received = timestamp()?;
+        let expires = received.saturating_add(ttl);
+        let tx = self.store.transaction()?;
+        let to_app = match to_app_uuid.as_ref() {
+            Some(to_app_uuid) => {
+                let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let to_app = tx.get_app(local_peer_id, to_app_uuid)?;
+                Some(to_app.ok_or_else(|| format!("Unknown app {to_app_uuid}"))?)
+            },
+            None => None,
+        };
+        let peer_id = tx.get_or_put_peer(&peer)?;
+        let app = tx.get_or_put_app(peer_id, &app_uuid)?;
+        tx.set_app_announcement(app, to_app, received, expires, &data, signature.as_ref())?;
+        tx.commit()?;
+        self.announce_subscribers_send(ResponseBody::AppAnnouncement {
+            peer,
+            app_uuid,
+            to_app_uuid,
+            data,
+            received,
+            expires,
+            signed_at: signature.as_ref().map(|s| s.signed_at),
+            verification: signature.as_ref().map_or(Verification::Unsigned, |s| s.verification),
+            signature: signature.map(|s| s.signature),
+        });
+        Ok(())
+    }
+
+    fn remove_announcement(&mut self, peer: &str, app_uuid: String) -> Result<(), Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        let app = match tx.get_peer(peer)? {
+            Some(peer_id) => tx.get_app(peer_id, &app_uuid)?,
+            None => None,
+        };
+        let removed = match app {
+            Some(app) => tx.take_app_announcement(app)?,
+            None => None,
+        };
+        tx.commit()?;
+        if let Some(announcement) = removed {
+            self.announcement_removed(announcement);
+        }
+        Ok(())
+    }
+
+    fn expire_announcements(&mut self) -> Result<(), Box<dyn Error>> {
+        let expired = {
+            let tx = self.store.transaction()?;
+            let expired = tx.take_expired_app_announcements(timestamp()?)?;
+            tx.commit()?;
+            expired
+        };
+        for announcement in expired {
+            self.announcement_removed(announcement);
+        }
+        Ok(())
+    }
+
+    fn announcement_removed(&self, announcement: AppAnnouncement) {
+        self.announce_subscribers_send(ResponseBody::AppAnnouncementRemoved {
+            peer: announcement.peer,
+            app_uuid: announcement.app_uuid,
+            to_app_uuid: announcement.to_app_uuid,
+        });
+    }
+
+    fn has_broadcasts(&mut self) -> Result<bool, Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        Ok(!tx.list_app_broadcasts()?.is_empty())
+    }
+
+    /// Sends every local app's broadcast announcement to a newly
+    /// connected peer.
+    fn push_broadcasts(&mut self, peer_id: PeerId) -> Result<(), Box<dyn Error>> {
+        let broadcasts = {
+            let tx = self.store.transaction()?;
+            tx.list_app_broadcasts()?
+        };
+        for broadcast in broadcasts {
+            self.announced_to.entry(broadcast.app_uuid.clone()).or_default().insert(peer_id);
+            let request = self.announce_request(broadcast.app_uuid, None, broadcast.data, broadcast.ttl)?;
+            self.swarm.behaviour_mut().request_response.send_request(&peer_id, request.into());
+        }
+        Ok(())
+    }
+
+    /// Connects to a peer so it is sent our broadcast announcements.
+    fn dial_for_broadcasts(&mut self, peer_id: PeerId) {
+        if self.swarm.is_connected(&peer_id) {
+            return;
+        }
+        let addresses: Vec<Multiaddr> = self.peers.get(&peer_id)
+            .map(|addrs| addrs.iter().cloned().collect())
+            .unwrap_or_default();
+        swarm::dial_peer(&mut self.swarm, peer_id, addresses, &self.settings.network.dial_transports);
+    }
+
+    fn broadcast_announce(&mut self, app_uuid: String, data: serde_json::Value, ttl: Option<u64>) -> Result<(), Box<dyn Error>> {
+        let local_peer = self.peer_id.to_base58();
+        {
+            let tx = self.store.transaction()?;
+            let peer_id = tx.get_or_put_peer(&local_peer)?;
+            let app = tx.get_or_put_app(peer_id, &app_uuid)?;
+            tx.set_app_broadcast(app, timestamp()?, ttl, &data)?;
+            tx.commit()?;
+        }
+        // Other peers are sent all our broadcasts once connected
+        self.send_broadcast(app_uuid, data, ttl)
+    }
+
+    /// Sends a broadcast announcement to local apps and every connected
+    /// peer.
+    fn send_broadcast(&mut self, app_uuid: String, data: serde_json::Value, ttl: Option<u64>) -> Result<(), Box<dyn Error>> {
+        // Local apps receive it like any other announcement
+        let signature = self.sign_announcement(&app_uuid, None, ttl, &data)?;
+        let local_peer = self.peer_id.to_base58();
+        self.receive_announcement(local_peer, app_uuid.clone(), None, data.clone(), ttl, Some(signature.clone()))?;
+        let connected: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        self.announced_to.entry(app_uuid.clone()).or_default().extend(&connected);
+        for peer in &connected {
+            self.swarm.behaviour_mut().request_response.send_request(peer, swarm::Request::Announce {
+                app_uuid: app_uuid.clone(),
+                to_app_uuid: None,
+                data: data.clone(),
+                ttl,
+                signature: Some(signature.signature.clone()),
+                signed_at: Some(signature.signed_at),
+            }.into());
+        }
+        self.broadcasts_sent.insert(app_uuid, timestamp()?);
+        Ok(())
+    }
+
+    /// Sends broadcasts again before half their TTL has passed, so they
+    /// don't expire on peers which stay connected.
+    fn refresh_broadcasts(&mut self) -> Result<(), Box<dyn Error>> {
+        let broadcasts = {
+            let tx = self.store.transaction()?;
+            tx.list_app_broadcasts()?
+        };
+        let now = timestamp()?;
+        let max_ttl = self.settings.limits.announcement_max_ttl_secs;
+        for broadcast in broadcasts {
+            let ttl = broadcast.ttl.map_or(max_ttl, |ttl| i64::try_from(ttl).unwrap_or(i64::MAX).min(max_ttl));
+            let sent = self.broadcasts_sent.get(&broadcast.app_uuid).copied().unwrap_or(0);
+            // Refresh on the last check before half the TTL has passed
+            let due = sent + ttl / 2 - BROADCAST_REFRESH_INTERVAL.as_secs() as i64;
+            if now >= due {
+                self.send_broadcast(broadcast.app_uuid, broadcast.data, broadcast.ttl)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops broadcasting an app's announcement and asks connected peers
+    /// and any it was sent to to forget it.
+    fn withdraw_announcement(&mut self, app_uuid: String) -> Result<(), Box<dyn Error>> {
+        let local_peer = self.peer_id.to_base58();
+        {
+            let tx = self.store.transaction()?;
+            let peer_id = tx.get_or_put_peer(&local_peer)?;
+            let Some(app) = tx.get_app(peer_id, &app_uuid)? else {
+                return Err(format!("Unknown app {app_uuid}").into());
+            };
+            tx.delete_app_broadcast(app)?;
+            tx.commit()?;
+        }
+        self.broadcasts_sent.remove(&app_uuid);
+        self.remove_announcement(&local_peer, app_uuid.clone())?;
+        let mut peers = self.announced_to.remove(&app_uuid).unwrap_or_default();
+        peers.extend(self.swarm.connected_peers());
+        for peer in peers {
+            self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::Withdraw {
+                app_uuid: app_uuid.clone(),
+            }.into());
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, app_uuid: String, topic: String, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if topic.is_empty() {
+            return Err("Topic must not be empty".into());
+        }
+        {
+            let tx = self.store.transaction()?;
+            let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+            if tx.get_app(peer_id, &app_uuid)?.is_none() {
+                return Err(format!("Unknown app {app_uuid}").into());
+            }
+        }
+        let app_topic = swarm::app_topic(&self.peer_id, &app_uuid, &topic);
+        match self.swarm.behaviour_mut().gossipsub.publish(app_topic.clone(), data.clone()) {
+            Ok(_) => {},
+            // Nobody on the network is subscribed yet, which isn't an
+            // error for the publishing app
+            Err(gossipsub::PublishError::InsufficientPeers) => {
+                println!("No peers subscribed to topic {app_topic}");
+            },
+            Err(err) => return Err(err.into()),
+        }
+        // Gossipsub doesn't deliver our own messages back to us
+        self.topic_subscribers_send(&app_topic.hash(), ResponseBody::TopicMessage {
+            peer: self.peer_id.to_base58(),
             app_uuid,
+            topic,
             data,
         });
         Ok(())
     }
 
-    fn send_message(&mut self, to_peer: String, to_uuid: String, from_uuid: String, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    async fn send_invite(&mut self, to_peer: String, to_uuid: String, from_uuid: String, data: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        let peer: PeerId = to_peer.parse()?;
         let tx = self.store.transaction()?;
-        let queued: i64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().try_into()?;
+        let from_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+        let to_peer_id = tx.get_or_put_peer(&to_peer)?;
+        let from = tx.get_app(from_peer_id, &from_uuid)?.ok_or("Cannot find 'from' app instance in database")?;
+        let to = tx.get_or_put_app(to_peer_id, &to_uuid)?;
+        tx.put_message_invite_sent(timestamp()?, from, to)?;
+        tx.commit()?;
+        let invite = PendingInvite::Invite {
+            inviter: from,
+            invitee: to,
+            invitee_uuid: to_uuid.clone(),
+        };
+        if peer == self.peer_id {
+            let response = self.receive_invite(peer, from_uuid, to_uuid, data).await?;
+            if let Some((kind, error)) = response_error(&response) {
+                self.invite_failed(peer, invite, kind, error)?;
+            }
+        } else {
+            let request_id = self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::Invite {
+                from_app_uuid: from_uuid,
+                to_app_uuid: to_uuid,
+                data,
+            }.into());
+            self.pending_invites.insert(request_id, invite);
+        }
+        Ok(())
+    }
+
+    /// Accepts or rejects a received invite and notifies the inviting
+    /// app. Accepting allows messages in both directions between the
+    /// two apps.
+    async fn respond_to_invite(&mut self, app_uuid: String, invite_id: usize, accepted: bool) -> Result<(), Box<dyn Error>> {
+        let tx = self.store.transaction()?;
+        let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+        let invitee = tx.get_or_put_app(local_peer_id, &app_uuid)?;
+        let inviter = tx.take_message_invite(invitee, invite_id.try_into()?)?.ok_or("Cannot find invite in database")?;
+        let (inviter_peer, inviter_uuid) = tx.get_app_peer_and_uuid(inviter)?.ok_or("Cannot find inviting app in database")?;
+        if accepted {
+            tx.put_message_allow(inviter, invitee)?;
+        }
+        tx.commit()?;
+        let peer: PeerId = inviter_peer.parse()?;
+        let invite = PendingInvite::Response {
+            invitee,
+            inviter,
+            inviter_uuid: inviter_uuid.clone(),
+            accepted,
+        };
+        if peer == self.peer_id {
+            let response = self.receive_invite_response(peer, app_uuid, inviter_uuid, accepted).await?;
+            if let Some((kind, error)) = response_error(&response) {
+                self.invite_failed(peer, invite, kind, error)?;
+            }
+        } else {
+            let request_id = self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::InviteResponse {
+                from_app_uuid: app_uuid,
+                to_app_uuid: inviter_uuid,
+                accepted,
+            }.into());
+            self.pending_invites.insert(request_id, invite);
+        }
+        Ok(())
+    }
+
+    async fn send_message(&mut self, to_peer: String, to_uuid: String, from_uuid: String, message: Vec<u8>) -> Result<i64, Box<dyn Error>> {
+        // Validate peer ID before queuing the message
+        to_peer.parse::<PeerId>()?;
+        let tx = self.store.transaction()?;
+        let queued = timestamp()?;
         let message_id = tx.get_or_put_message_data(&message)?;
         let from_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
         let to_peer_id = tx.get_or_put_peer(&to_peer)?;
         let from = tx.get_app(from_peer_id, &from_uuid)?.ok_or("Cannot find 'from' app instance in database")?;
         let to = tx.get_app(to_peer_id, &to_uuid)?.ok_or("Cannot find 'to' app instance in database")?;
-        let outbox_id = tx.put_message_outbox(queued, from, to, message_id)?;
-        let peer: PeerId = to_peer.parse()?;
-        let request_id = self.swarm.behaviour_mut().request_response.send_request(&peer, swarm::Request::Message {
-            to_app_uuid: to_uuid,
+        let expires = queued.saturating_add(self.settings.limits.delivery_expiry_secs);
+        let outbox_id = tx.put_message_outbox(queued, expires, from, to, message_id)?;
+        tx.commit()?;
+        self.deliver_messages(vec![QueuedMessage {
+            id: outbox_id,
+            peer: to_peer,
             from_app_uuid: from_uuid,
+            to_app_uuid: to_uuid,
             message,
-        });
-        self.delivery_attempts.insert(request_id, outbox_id);
-        Ok(tx.commit()?)
+            attempts: 0,
+            expires: Some(expires),
+        }]).await?;
+        Ok(outbox_id)
     }
 
     // fn read_message(&mut self, uuid: String) -> Result<Option<Message>, Box<dyn Error>> {
@@ -408,53 +1721,79 @@ impl Server {
                 }).await;
             },
             RequestBody::Peers => {
-                let mut peers: Vec<String> = Vec::new();
-                for (id, _addrs) in self.peers.iter() {
-                    peers.push(id.to_base58());
+                // Include connected peers we have no listen address for,
+                // e.g. ones which dialed us
+                let mut ids: Vec<PeerId> = self.peers.keys()
+                    .chain(self.swarm.connected_peers())
+                    .copied()
+                    .collect::<HashSet<PeerId>>()
+                    .into_iter()
+                    .collect();
+                ids.sort();
+                let mut peers = Vec::new();
+                for id in ids {
+                    peers.push(self.peer_info(id)?);
                 }
                 let _ = request.response.send(ResponseBody::Peers {peers}).await;
             },
+            RequestBody::PeerInfo {peer} => {
+                let info = self.peer_info(peer.parse()?)?;
+                if !info.discovered && !info.connected && info.addresses.is_empty() && info.agent_version.is_none() {
+                    return Err("Unknown peer".into());
+                }
+                let _ = request.response.send(ResponseBody::PeerInfo(info)).await;
+            },
+            RequestBody::ListenAddresses => {
+                let addresses = self.listen_addresses.iter()
+                    .map(|addr| addr.to_string())
+                    .collect();
+                let _ = request.response.send(ResponseBody::ListenAddresses {addresses}).await;
+            },
             RequestBody::DialAddress {address} => {
                 let remote = address.parse::<Multiaddr>()?;
-                self.swarm.dial(remote)?;
-                // TODO: use connection id to track success or not of
-                // Indentify by checking for outgoing connection error,
-                // connection established, and identify sent/received events.
-                // TODO: use identify::Behaviour::push() to actively push
-                // identity info to peer after this dial request.
-                let _ = request.response.send(ResponseBody::Success).await;
+                let opts = DialOpts::from(remote.clone());
+                let connection_id = opts.connection_id();
+                self.swarm.dial(opts)?;
+                // Responds when the connection fails, or is established
+                // and the peer identified
+                self.pending_dials.insert(connection_id, PendingDial {
+                    address: remote,
+                    started: tokio::time::Instant::now(),
+                    peer_id: None,
+                    response: request.response,
+                });
             },
-            RequestBody::AppAnnouncements => {
+            RequestBody::AppAnnouncements {peer, app_uuid, to_app_uuid, since} => {
+                let filter = AnnouncementFilter {peer, app_uuid, to_app_uuid};
                 let tx = self.store.transaction()?;
-                let _ = request.response.send(ResponseBody::AppAnnouncements {
-                    announcements: tx.list_app_announcements()?,
-                }).await;
+                let announcements = tx.list_app_announcements(timestamp()?, &filter, since)?;
+                let _ = request.response.send(ResponseBody::AppAnnouncements {announcements}).await;
             },
-            RequestBody::Announce {peer, app_uuid, data} => {
+            RequestBody::Announce {peer, app_uuid, to_app_uuid, data, ttl} => {
                 // If the announce is directed at local peer just write directly to database
                 if peer.eq(&self.peer_id.to_base58()) {
-                    // Can't store u64 timestamp directly in sqlite, would have to store as blob
-                    let received: i64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().try_into()?;
-                    let tx = self.store.transaction()?;
-                    let peer_id = tx.get_or_put_peer(&peer)?;
-                    let app = tx.get_or_put_app(peer_id, &app_uuid)?;
-                    tx.set_app_announcement(app, received, &data)?;
-                    tx.commit()?;
-                    self.announce_subscribers_send(ResponseBody::AppAnnouncement {
-                        peer,
-                        app_uuid,
-                        data,
-                    }).await;
+                    let signature = self.sign_announcement(&app_uuid, to_app_uuid.as_deref(), ttl, &data)?;
+                    self.receive_announcement(peer, app_uuid, to_app_uuid, data, ttl, Some(signature))?;
                 } else {
                     // Else if announce is directed at another peer, send libp2p request
-                    self.send_announce(&peer, app_uuid, data)?;
+                    self.send_announce(&peer, app_uuid, to_app_uuid, data, ttl)?;
                 }
                 let _ = request.response.send(ResponseBody::Success).await;
             },
-            RequestBody::SendMessage {peer, app_uuid, from_app_uuid, message} => {
-                self.send_message(peer, app_uuid, from_app_uuid, message)?;
+            RequestBody::BroadcastAnnounce {app_uuid, data, ttl} => {
+                self.broadcast_announce(app_uuid, data, ttl)?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::WithdrawAnnouncement {app_uuid} => {
+                self.withdraw_announcement(app_uuid)?;
                 let _ = request.response.send(ResponseBody::Success).await;
             },
+            RequestBody::SendMessage {peer, app_uuid, from_app_uuid, message} => {
+                let id = self.send_message(peer, app_uuid, from_app_uuid, message).await?;
+                let _ = request.response.send(ResponseBody::SendMessage {
+                    id: id.try_into()?,
+                }).await;
+            },
             RequestBody::InboxMessages {app_uuid} => {
                 let tx = self.store.transaction()?;
                 let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
@@ -468,20 +1807,193 @@ impl Server {
                 let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
                 let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
                 tx.delete_inbox_message(app_id, message_id.try_into()?)?;
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::OutboxMessages {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let mut messages = tx.list_app_outbox_messages(app_id)?;
+                let in_flight: HashSet<usize> = self.delivery_attempts.values()
+                    .filter_map(|outbox_id| usize::try_from(*outbox_id).ok())
+                    .collect();
+                for message in messages.iter_mut() {
+                    if in_flight.contains(&message.id) {
+                        message.state = OutboxState::InFlight;
+                    }
+                }
+                let _ = request.response.send(ResponseBody::OutboxMessages {messages}).await;
+            },
+            RequestBody::DeleteOutboxMessage {app_uuid, message_id} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                tx.delete_outbox_message(app_id, message_id.try_into()?)?;
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::GetInboxQuota {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let (max_messages, max_peer_messages) = tx.get_inbox_quota(app_id)?;
+                let _ = request.response.send(ResponseBody::GetInboxQuota {
+                    max_messages: max_messages.unwrap_or(self.settings.limits.inbox_max_messages),
+                    max_peer_messages: max_peer_messages.unwrap_or(self.settings.limits.inbox_max_peer_messages),
+                }).await;
+            },
+            RequestBody::SetInboxQuota {app_uuid, max_messages, max_peer_messages} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                tx.set_inbox_quota(app_id, max_messages, max_peer_messages)?;
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::RestrictMessages {app_uuid, restricted} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                tx.set_app_messages_restricted(app_id, restricted)?;
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::AllowMessages {app_uuid, peer, from_app_uuid} => {
+                peer.parse::<PeerId>()?;
+                let tx = self.store.transaction()?;
+                let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let to = tx.get_or_put_app(local_peer_id, &app_uuid)?;
+                let from_peer_id = tx.get_or_put_peer(&peer)?;
+                let from = tx.get_or_put_app(from_peer_id, &from_app_uuid)?;
+                tx.put_message_allow(from, to)?;
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::DisallowMessages {app_uuid, peer, from_app_uuid} => {
+                let tx = self.store.transaction()?;
+                let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let to = tx.get_or_put_app(local_peer_id, &app_uuid)?;
+                if let Some(from_peer_id) = tx.get_peer(&peer)? {
+                    if let Some(from) = tx.get_app(from_peer_id, &from_app_uuid)? {
+                        tx.delete_message_allow(from, to)?;
+                    }
+                }
+                tx.commit()?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::MessageAllowList {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let _ = request.response.send(ResponseBody::MessageAllowList {
+                    restricted: tx.get_app_messages_restricted(app_id)?,
+                    allowed: tx.list_message_allow(app_id)?,
+                }).await;
+            },
+            RequestBody::SendInvite {peer, app_uuid, from_app_uuid, data} => {
+                self.send_invite(peer, app_uuid, from_app_uuid, data).await?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::Invites {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let _ = request.response.send(ResponseBody::Invites {
+                    invites: tx.list_app_message_invites(app_id)?,
+                }).await;
+            },
+            RequestBody::AcceptInvite {app_uuid, invite_id} => {
+                self.respond_to_invite(app_uuid, invite_id, true).await?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::RejectInvite {app_uuid, invite_id} => {
+                self.respond_to_invite(app_uuid, invite_id, false).await?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::Publish {app_uuid, topic, data} => {
+                self.publish(app_uuid, topic, data)?;
+                let _ = request.response.send(ResponseBody::Success).await;
+            },
+            RequestBody::Cancel {request_id} => {
+                let key = (request.client_id, request_id);
+                if self.remove_subscribers(|k| *k == key) == 0 {
+                    return Err(format!("No subscription for request {}", request_id).into());
+                }
                 let _ = request.response.send(ResponseBody::Success).await;
             },
             RequestBody::SubscribePeerEvents => {
-                self.peer_subscribers.insert(request.request.id, request.response);
+                self.peer_subscribers.subscribe((request.client_id, request.request.id), request.response);
             },
-            RequestBody::SubscribeAnnounceEvents => {
-                self.announce_subscribers.insert(request.request.id, request.response);
+            RequestBody::SubscribeAnnounceEvents {peer, app_uuid, to_app_uuid} => {
+                let subscribers = self.announce_subscribers
+                    .entry(AnnouncementFilter {peer, app_uuid, to_app_uuid})
+                    .or_insert_with(|| Subscribers::new(self.settings.limits.event_buffer_size));
+                subscribers.subscribe((request.client_id, request.request.id), request.response);
             },
             RequestBody::SubscribeInboxEvents {app_uuid} => {
                 let tx = self.store.transaction()?;
                 let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
                 let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
-                let subscribers = self.inbox_subscribers.entry(app_id).or_insert(HashMap::new());
-                subscribers.insert(request.request.id, request.response);
+                let subscribers = self.inbox_subscribers
+                    .entry(app_id)
+                    .or_insert_with(|| Subscribers::new(self.settings.limits.event_buffer_size));
+                subscribers.subscribe((request.client_id, request.request.id), request.response);
+            },
+            RequestBody::SubscribeOutboxEvents {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let subscribers = self.outbox_subscribers
+                    .entry(app_id)
+                    .or_insert_with(|| Subscribers::new(self.settings.limits.event_buffer_size));
+                subscribers.subscribe((request.client_id, request.request.id), request.response);
+            },
+            RequestBody::SubscribeInviteEvents {app_uuid} => {
+                let tx = self.store.transaction()?;
+                let peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                let app_id = tx.get_or_put_app(peer_id, &app_uuid)?;
+                let subscribers = self.invite_subscribers
+                    .entry(app_id)
+                    .or_insert_with(|| Subscribers::new(self.settings.limits.event_buffer_size));
+                subscribers.subscribe((request.client_id, request.request.id), request.response);
+            },
+            RequestBody::SubscribeTopic {app_uuid, peer, publisher_uuid, topic} => {
+                if topic.is_empty() {
+                    return Err("Topic must not be empty".into());
+                }
+                let peer: PeerId = match peer {
+                    Some(peer) => peer.parse()?,
+                    None => self.peer_id,
+                };
+                let publisher_uuid = publisher_uuid.unwrap_or_else(|| app_uuid.clone());
+                {
+                    let tx = self.store.transaction()?;
+                    let local_peer_id = tx.get_or_put_peer(&self.peer_id.to_base58())?;
+                    let Some(subscriber) = tx.get_app(local_peer_id, &app_uuid)? else {
+                        return Err(format!("Unknown app {app_uuid}").into());
+                    };
+                    let publisher = match tx.get_peer(&peer.to_base58())? {
+                        Some(peer_id) => tx.get_app(peer_id, &publisher_uuid)?,
+                        None => None,
+                    };
+                    let Some(publisher) = publisher else {
+                        return Err(format!("Unknown app {publisher_uuid}").into());
+                    };
+                    // Apps can't follow each other's topics without
+                    // being allowed to, e.g. by accepting an invite
+                    if publisher != subscriber && !tx.has_message_allow(publisher, subscriber)? {
+                        return Err(format!("App {publisher_uuid} is not on the allow-list of {app_uuid}").into());
+                    }
+                }
+                let app_topic = swarm::app_topic(&peer, &publisher_uuid, &topic);
+                if !self.topic_subscribers.contains_key(&app_topic.hash()) {
+                    self.swarm.behaviour_mut().gossipsub.subscribe(&app_topic)?;
+                }
+                let subscribers = self.topic_subscribers
+                    .entry(app_topic.hash())
+                    .or_insert_with(|| Subscribers::new(self.settings.limits.event_buffer_size));
+                subscribers.subscribe((request.client_id, request.request.id), request.response);
             }
         }
         Ok(())
@@ -0,0 +1,143 @@
+use libp2p::{identity::{Keypair, PublicKey}, PeerId};
+use serde::Serialize;
+use std::error::Error;
+
+use crate::protocol::Verification;
+
+/// Prefixed to signed content so signatures can't be reused for
+/// anything else signed with the peer's key, e.g. libp2p records.
+const SIGNING_DOMAIN: &[u8] = b"mutiny-signed-v1\0";
+
+/// Multihash code used by peer IDs which embed the public key.
+const IDENTITY_MULTIHASH: u64 = 0x00;
+
+/// The parts of a request covered by the sending peer's signature.
+/// Signatures are kept with stored content so it can be verified again
+/// after being passed on by another peer. The signing peer and time are
+/// included so a signature can't be passed off as another peer's, or
+/// replayed once the content is stale.
+#[derive(Serialize, Debug)]
+pub enum SignedContent<'a> {
+    Announce {
+        peer: &'a str,
+        signed_at: i64,
+        app_uuid: &'a str,
+        to_app_uuid: Option<&'a str>,
+        ttl: Option<u64>,
+        data: &'a serde_json::Value,
+    },
+    Message {
+        peer: &'a str,
+        signed_at: i64,
+        from_app_uuid: &'a str,
+        to_app_uuid: &'a str,
+        #[serde(with = "serde_bytes")]
+        message: &'a [u8],
+    },
+}
+
+/// A peer's signature over stored content.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Signature {
+    pub signature: Vec<u8>,
+    /// When the peer signed the content, as covered by the signature
+    pub signed_at: i64,
+    /// Either `Verified` or `Unverifiable`, invalid signatures aren't kept
+    pub verification: Verification,
+}
+
+impl SignedContent<'_> {
+    fn encode(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        let mut encoded = SIGNING_DOMAIN.to_vec();
+        rmp_serde::encode::write_named(&mut encoded, self)?;
+        Ok(encoded)
+    }
+}
+
+pub fn sign(keypair: &Keypair, content: &SignedContent) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(keypair.sign(&content.encode()?)?)
+}
+
+/// Checks `signature` was made over `content` by `peer`, returning None
+/// if it wasn't. Peer IDs of e.g. Ed25519 keys embed the public key,
+/// others (e.g. RSA) are checked using `known_key` if it is the peer's,
+/// and are `Unverifiable` without it.
+pub fn verify(
+    peer: &PeerId,
+    known_key: Option<&PublicKey>,
+    content: &SignedContent,
+    signature: &[u8],
+) -> Option<Verification> {
+    let known_key = known_key.filter(|key| key.to_peer_id() == *peer).cloned();
+    let Some(public_key) = public_key(peer).or(known_key) else {
+        return Some(Verification::Unverifiable);
+    };
+    let encoded = content.encode().ok()?;
+    public_key.verify(&encoded, signature).then_some(Verification::Verified)
+}
+
+fn public_key(peer: &PeerId) -> Option<PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signed_content() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        let peer_str = peer.to_base58();
+        let data = serde_json::json!({"name": "alice", "age": 30});
+        let announce = |peer, signed_at, to_app_uuid, ttl, data| SignedContent::Announce {
+            peer,
+            signed_at,
+            app_uuid: "app1",
+            to_app_uuid,
+            ttl,
+            data,
+        };
+        let content = announce(&peer_str, 100, None, Some(60), &data);
+        let signature = sign(&keypair, &content).unwrap();
+        assert_eq!(verify(&peer, None, &content, &signature), Some(Verification::Verified));
+
+        // Different content
+        let tampered = serde_json::json!({"name": "mallory", "age": 30});
+        let check = |content| verify(&peer, None, &content, &signature);
+        assert_eq!(check(announce(&peer_str, 100, None, Some(60), &tampered)), None);
+        assert_eq!(check(announce(&peer_str, 100, Some("app2"), Some(60), &data)), None);
+        assert_eq!(check(announce(&peer_str, 200, None, Some(60), &data)), None);
+        assert_eq!(check(announce(&peer_str, 100, None, None, &data)), None);
+
+        // Different peer
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+        assert_eq!(verify(&other, None, &content, &signature), None);
+        let other_str = other.to_base58();
+        assert_eq!(check(announce(&other_str, 100, None, Some(60), &data)), None);
+    }
+
+    #[test]
+    fn unknown_keys_are_unverifiable() {
+        let keypair = Keypair::generate_ed25519();
+        let content = SignedContent::Message {
+            peer: "peer",
+            signed_at: 100,
+            from_app_uuid: "app1",
+            to_app_uuid: "app2",
+            message: b"hello",
+        };
+        let signature = sign(&keypair, &content).unwrap();
+        // Doesn't embed a public key, and the known key isn't the peer's
+        let peer = PeerId::random();
+        assert_eq!(verify(&peer, None, &content, &signature), Some(Verification::Unverifiable));
+        assert_eq!(
+            verify(&peer, Some(&keypair.public()), &content, &signature),
+            Some(Verification::Unverifiable),
+        );
+    }
+}
@@ -1,7 +1,65 @@
 use rusqlite::{self, params, Connection, Result, Transaction, OptionalExtension};
 use uuid::Uuid;
 
-use crate::protocol::{Message, AppAnnouncement};
+use crate::protocol::{Message, AppAnnouncement, AnnouncementFilter, OutboxMessage, OutboxState, MessageAllow, Invite, AddressSource, PeerAddress, DeliveryError, Verification};
+use crate::signing::Signature;
+
+/// A local app's announcement, sent to every peer it connects to.
+#[derive(Debug, PartialEq)]
+pub struct AppBroadcast {
+    pub app_uuid: String,
+    pub data: serde_json::Value,
+    pub ttl: Option<u64>,
+}
+
+/// A queued message in the outbox awaiting delivery to a remote app.
+pub struct QueuedMessage {
+    pub id: i64,
+    pub peer: String,
+    pub from_app_uuid: String,
+    pub to_app_uuid: String,
+    pub message: Vec<u8>,
+    pub attempts: i64,
+    pub expires: Option<i64>,
+}
+
+impl rusqlite::types::FromSql for AddressSource {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "mdns" => Ok(AddressSource::Mdns),
+            "identify" => Ok(AddressSource::Identify),
+            "dht" => Ok(AddressSource::Dht),
+            "dial" => Ok(AddressSource::Dial),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl rusqlite::types::FromSql for DeliveryError {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "unreachable" => Ok(DeliveryError::Unreachable),
+            "unknown_app" => Ok(DeliveryError::UnknownApp),
+            "not_allowed" => Ok(DeliveryError::NotAllowed),
+            "quota_exceeded" => Ok(DeliveryError::QuotaExceeded),
+            "unsupported_version" => Ok(DeliveryError::UnsupportedVersion),
+            "internal_error" => Ok(DeliveryError::InternalError),
+            "expired" => Ok(DeliveryError::Expired),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl rusqlite::types::FromSql for Verification {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "unsigned" => Ok(Verification::Unsigned),
+            "unverifiable" => Ok(Verification::Unverifiable),
+            "verified" => Ok(Verification::Verified),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
+    }
+}
 
 pub struct Store {
     db: Connection,
@@ -18,9 +76,19 @@ impl Store {
         })
     }
 
+    /// Removes unreferenced message data and rebuilds the database
+    /// file to reclaim the space used by deleted rows.
+    pub fn compact(&mut self) -> Result<()> {
+        let tx = self.transaction()?;
+        tx.prune_message_data()?;
+        tx.commit()?;
+        // VACUUM cannot be run from within a transaction
+        self.db.execute_batch("VACUUM")
+    }
+
     pub fn generate_app_uuid() -> String {
         let buffer = &mut Uuid::encode_buffer();
-        return Uuid::new_v4().hyphenated().encode_lower(buffer).to_owned();
+        Uuid::new_v4().hyphenated().encode_lower(buffer).to_owned()
     }
 }
 
@@ -34,7 +102,7 @@ impl<'a> StoreTransaction<'a> {
             "SELECT user_version
              FROM pragma_user_version",
         )?;
-        return stmt.query_row([], |row| row.get::<_, i64>(0));
+        stmt.query_row([], |row| row.get::<_, i64>(0))
     }
 
     // fn set_version(&self, version: i64) -> Result<()> {
@@ -150,6 +218,159 @@ impl<'a> StoreTransaction<'a> {
                          PRAGMA user_version = 4;"
                     )?;
                 },
+                4 => {
+                    // Outbox delivery retries
+                    println!("Migrating database to version 5");
+                    self.tx.execute_batch(
+                        "ALTER TABLE message_outbox ADD attempts INTEGER NOT NULL DEFAULT 0;
+                         ALTER TABLE message_outbox ADD next_attempt INTEGER NOT NULL DEFAULT 0;
+                         ALTER TABLE message_outbox ADD expires INTEGER;
+                         ALTER TABLE message_outbox ADD failed INTEGER;
+                         PRAGMA user_version = 5;"
+                    )?;
+                },
+                5 => {
+                    // Outbox delivery failure reasons
+                    println!("Migrating database to version 6");
+                    self.tx.execute_batch(
+                        "ALTER TABLE message_outbox ADD last_error TEXT;
+                         PRAGMA user_version = 6;"
+                    )?;
+                },
+                6 => {
+                    // Outbox delivery status
+                    println!("Migrating database to version 7");
+                    self.tx.execute_batch(
+                        "ALTER TABLE message_outbox ADD delivered INTEGER;
+                         PRAGMA user_version = 7;"
+                    )?;
+                },
+                7 => {
+                    // Per-app inbox quotas
+                    println!("Migrating database to version 8");
+                    self.tx.execute_batch(
+                        "CREATE TABLE app_inbox_quota (
+                             app_id INTEGER PRIMARY KEY REFERENCES app(id),
+                             max_messages INTEGER,
+                             max_peer_messages INTEGER
+                         );
+                         PRAGMA user_version = 8;"
+                    )?;
+                },
+                8 => {
+                    // Reintroduce message allow-lists
+                    println!("Migrating database to version 9");
+                    self.tx.execute_batch(
+                        "CREATE TABLE app_message_policy (
+                             app_id INTEGER PRIMARY KEY REFERENCES app(id),
+                             restricted INTEGER NOT NULL
+                         );
+                         CREATE TABLE message_allow (
+                             from_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             to_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             PRIMARY KEY (from_app_id, to_app_id)
+                         );
+                         PRAGMA user_version = 9;"
+                    )?;
+                },
+                9 => {
+                    // Invitations to establish app-to-app contact
+                    println!("Migrating database to version 10");
+                    self.tx.execute_batch(
+                        "CREATE TABLE message_invite (
+                             id INTEGER PRIMARY KEY AUTOINCREMENT,
+                             received INTEGER NOT NULL,
+                             from_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             to_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             data TEXT NOT NULL,
+                             UNIQUE(from_app_id, to_app_id)
+                         );
+                         CREATE TABLE message_invite_sent (
+                             from_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             to_app_id INTEGER REFERENCES app(id) NOT NULL,
+                             sent INTEGER NOT NULL,
+                             PRIMARY KEY (from_app_id, to_app_id)
+                         );
+                         PRAGMA user_version = 10;"
+                    )?;
+                },
+                10 => {
+                    // Remember peer addresses across restarts
+                    println!("Migrating database to version 11");
+                    self.tx.execute_batch(
+                        "CREATE TABLE peer_address (
+                             peer_id INTEGER REFERENCES peer(id) NOT NULL,
+                             address TEXT NOT NULL,
+                             source TEXT NOT NULL,
+                             last_seen INTEGER NOT NULL,
+                             PRIMARY KEY (peer_id, address)
+                         );
+                         PRAGMA user_version = 11;"
+                    )?;
+                },
+                11 => {
+                    // Record why delivery failed, not just the message
+                    println!("Migrating database to version 12");
+                    self.tx.execute_batch(
+                        "ALTER TABLE message_outbox ADD last_error_kind TEXT;
+                         PRAGMA user_version = 12;"
+                    )?;
+                },
+                12 => {
+                    // Announcements pushed to every peer
+                    println!("Migrating database to version 13");
+                    self.tx.execute_batch(
+                        "CREATE TABLE app_broadcast (
+                             app_id INTEGER PRIMARY KEY REFERENCES app(id),
+                             updated INTEGER NOT NULL,
+                             data TEXT NOT NULL
+                         );
+                         PRAGMA user_version = 13;"
+                    )?;
+                },
+                13 => {
+                    // Announcement expiry. Existing announcements are
+                    // left without one until the server applies its
+                    // maximum TTL, see `set_unknown_announcement_expiry`.
+                    println!("Migrating database to version 14");
+                    self.tx.execute_batch(
+                        "ALTER TABLE app_announcement ADD expires INTEGER;
+                         ALTER TABLE app_broadcast ADD ttl INTEGER;
+                         PRAGMA user_version = 14;"
+                    )?;
+                },
+                14 => {
+                    // Announcements sent to a particular app
+                    println!("Migrating database to version 15");
+                    self.tx.execute_batch(
+                        "ALTER TABLE app_announcement ADD to_app_id INTEGER REFERENCES app(id);
+                         PRAGMA user_version = 15;"
+                    )?;
+                },
+                15 => {
+                    // Signatures from the sending peer. Kept with the
+                    // inbox entry as message data is shared.
+                    println!("Migrating database to version 16");
+                    self.tx.execute_batch(
+                        "ALTER TABLE app_announcement ADD signature BLOB;
+                         ALTER TABLE message_inbox ADD signature BLOB;
+                         PRAGMA user_version = 16;"
+                    )?;
+                },
+                16 => {
+                    // Signing time, and signatures which couldn't be
+                    // verified. Earlier signatures were all verified.
+                    println!("Migrating database to version 17");
+                    self.tx.execute_batch(
+                        "ALTER TABLE app_announcement ADD signed_at INTEGER;
+                         ALTER TABLE app_announcement ADD verification TEXT NOT NULL DEFAULT 'unsigned';
+                         UPDATE app_announcement SET verification = 'verified' WHERE signature IS NOT NULL;
+                         ALTER TABLE message_inbox ADD signed_at INTEGER;
+                         ALTER TABLE message_inbox ADD verification TEXT NOT NULL DEFAULT 'unsigned';
+                         UPDATE message_inbox SET verification = 'verified' WHERE signature IS NOT NULL;
+                         PRAGMA user_version = 17;"
+                    )?;
+                },
                 _ => break,
             }
         }
@@ -196,6 +417,19 @@ impl<'a> StoreTransaction<'a> {
         self.put_app(peer_id, uuid)
     }
 
+    /// Returns the peer ID and UUID of an app.
+    pub fn get_app_peer_and_uuid(&self, app_id: i64) -> Result<Option<(String, String)>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT peer.peer_id, app.uuid
+             FROM app
+             JOIN peer ON peer.id = app.peer_id
+             WHERE app.id = ?1",
+        )?;
+        stmt.query_row([app_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        }).optional()
+    }
+
     pub fn get_app_by_label(&self, label: &str) -> Result<Option<i64>> {
         let mut stmt = self.tx.prepare_cached(
             "SELECT app_id
@@ -233,6 +467,185 @@ impl<'a> StoreTransaction<'a> {
         Ok(())
     }
 
+    /// Returns the app's inbox quota overrides as (max_messages,
+    /// max_peer_messages), where None means the daemon default applies.
+    pub fn get_inbox_quota(&self, app_id: i64) -> Result<(Option<u32>, Option<u32>)> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT max_messages, max_peer_messages
+             FROM app_inbox_quota
+             WHERE app_id = ?1",
+        )?;
+        let quota = stmt.query_row([app_id], |row| {
+            Ok((row.get::<_, Option<u32>>(0)?, row.get::<_, Option<u32>>(1)?))
+        }).optional()?;
+        Ok(quota.unwrap_or((None, None)))
+    }
+
+    pub fn set_inbox_quota(&self, app_id: i64, max_messages: Option<u32>, max_peer_messages: Option<u32>) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO app_inbox_quota (app_id, max_messages, max_peer_messages)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT (app_id) DO UPDATE SET max_messages=?2, max_peer_messages=?3",
+        )?;
+        stmt.execute(params![app_id, max_messages, max_peer_messages])?;
+        Ok(())
+    }
+
+    /// Returns true if the app only accepts messages from apps on its
+    /// allow-list.
+    pub fn get_app_messages_restricted(&self, app_id: i64) -> Result<bool> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT restricted
+             FROM app_message_policy
+             WHERE app_id = ?1",
+        )?;
+        Ok(stmt.query_row([app_id], |row| row.get::<_, bool>(0)).optional()?.unwrap_or(false))
+    }
+
+    pub fn set_app_messages_restricted(&self, app_id: i64, restricted: bool) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO app_message_policy (app_id, restricted)
+             VALUES (?1, ?2)
+             ON CONFLICT (app_id) DO UPDATE SET restricted=?2",
+        )?;
+        stmt.execute(params![app_id, restricted])?;
+        Ok(())
+    }
+
+    pub fn put_message_allow(&self, from: i64, to: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO message_allow (from_app_id, to_app_id)
+             VALUES (?1, ?2)
+             ON CONFLICT DO NOTHING",
+        )?;
+        stmt.execute([from, to])?;
+        Ok(())
+    }
+
+    pub fn delete_message_allow(&self, from: i64, to: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_allow
+             WHERE from_app_id = ?1 AND to_app_id = ?2",
+        )?;
+        stmt.execute([from, to])?;
+        Ok(())
+    }
+
+    /// Returns true if the 'to' app accepts messages from the 'from'
+    /// app, either because it is unrestricted or has explicitly allowed
+    /// the 'from' app.
+    pub fn is_message_allowed(&self, from: i64, to: i64) -> Result<bool> {
+        if !self.get_app_messages_restricted(to)? {
+            return Ok(true);
+        }
+        self.has_message_allow(from, to)
+    }
+
+    /// Returns true if the 'from' app is on the 'to' app's allow-list,
+    /// whether or not the 'to' app is restricted.
+    pub fn has_message_allow(&self, from: i64, to: i64) -> Result<bool> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT 1
+             FROM message_allow
+             WHERE from_app_id = ?1 AND to_app_id = ?2",
+        )?;
+        Ok(stmt.query_row([from, to], |_row| Ok(())).optional()?.is_some())
+    }
+
+    pub fn list_message_allow(&self, to: i64) -> Result<Vec<MessageAllow>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT peer.peer_id, app.uuid
+             FROM message_allow
+             JOIN app ON app.id = from_app_id
+             JOIN peer ON peer.id = app.peer_id
+             WHERE to_app_id = ?1
+             ORDER BY peer.peer_id, app.uuid",
+        )?;
+        let rows = stmt.query_map([to], |row| {
+            Ok(MessageAllow {
+                peer: row.get::<_, String>(0)?,
+                app_uuid: row.get::<_, String>(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Stores a received invite, replacing any pending invite between
+    /// the same two apps.
+    pub fn put_message_invite(&self, received: i64, from: i64, to: i64, data: &serde_json::Value) -> Result<i64> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO message_invite (received, from_app_id, to_app_id, data)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (from_app_id, to_app_id) DO UPDATE SET received=?1, data=?4
+             RETURNING id",
+        )?;
+        stmt.query_row(params![received, from, to, data.to_string()], |row| row.get::<_, i64>(0))
+    }
+
+    /// Counts pending invites sent by any app on the given peer.
+    pub fn count_peer_message_invites(&self, peer_id: i64) -> Result<i64> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT COUNT(*)
+             FROM message_invite
+             JOIN app ON app.id = from_app_id
+             WHERE app.peer_id = ?1",
+        )?;
+        stmt.query_row([peer_id], |row| row.get::<_, i64>(0))
+    }
+
+    pub fn list_app_message_invites(&self, to: i64) -> std::result::Result<Vec<Invite>, Box<dyn std::error::Error>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT message_invite.id, peer.peer_id, app.uuid, data
+             FROM message_invite
+             JOIN app ON app.id = from_app_id
+             JOIN peer ON peer.id = app.peer_id
+             WHERE to_app_id = ?1
+             ORDER BY message_invite.id ASC",
+        )?;
+        let mut rows = stmt.query([to])?;
+        let mut results = Vec::new();
+        while let Some(row) = rows.next()? {
+            let raw_data: String = row.get(3)?;
+            results.push(Invite {
+                id: row.get::<_, usize>(0)?,
+                peer: row.get::<_, String>(1)?,
+                app_uuid: row.get::<_, String>(2)?,
+                data: serde_json::from_str(&raw_data)?,
+            });
+        }
+        Ok(results)
+    }
+
+    /// Deletes a pending invite, returning the ID of the inviting app.
+    pub fn take_message_invite(&self, to: i64, invite_id: i64) -> Result<Option<i64>> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_invite
+             WHERE to_app_id = ?1 AND id = ?2
+             RETURNING from_app_id",
+        )?;
+        stmt.query_row([to, invite_id], |row| row.get::<_, i64>(0)).optional()
+    }
+
+    pub fn put_message_invite_sent(&self, sent: i64, from: i64, to: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO message_invite_sent (from_app_id, to_app_id, sent)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT (from_app_id, to_app_id) DO UPDATE SET sent=?3",
+        )?;
+        stmt.execute([from, to, sent])?;
+        Ok(())
+    }
+
+    /// Deletes the record of an invite sent from one app to another,
+    /// returning true if such an invite was outstanding.
+    pub fn take_message_invite_sent(&self, from: i64, to: i64) -> Result<bool> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_invite_sent
+             WHERE from_app_id = ?1 AND to_app_id = ?2",
+        )?;
+        Ok(stmt.execute([from, to])? > 0)
+    }
+
     pub fn get_peer(&self, peer_id: &str) -> Result<Option<i64>> {
         let mut stmt = self.tx.prepare_cached(
             "SELECT id
@@ -258,6 +671,80 @@ impl<'a> StoreTransaction<'a> {
         self.put_peer(peer_id)
     }
 
+    /// Records an address for a peer, updating its last seen time if it
+    /// is already known. The source is where the address was first seen.
+    pub fn put_peer_address(&self, peer_id: i64, address: &str, source: AddressSource, last_seen: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO peer_address (peer_id, address, source, last_seen)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer_id, address)
+             DO UPDATE SET last_seen = ?4",
+        )?;
+        stmt.execute(params![peer_id, address, source.as_str(), last_seen])?;
+        Ok(())
+    }
+
+    /// Lists a peer's addresses seen at or after `since`, most recent first.
+    pub fn list_peer_addresses(&self, peer_id: &str, since: i64) -> Result<Vec<String>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT address
+             FROM peer_address
+             JOIN peer ON peer.id = peer_address.peer_id
+             WHERE peer.peer_id = ?1 AND last_seen >= ?2
+             ORDER BY last_seen DESC",
+        )?;
+        let rows = stmt.query_map(params![peer_id, since], |row| row.get::<_, String>(0))?;
+        rows.collect()
+    }
+
+    /// Lists addresses seen at or after `since` for peers with undelivered
+    /// outbox messages as (peer ID, address) pairs, most recent first.
+    pub fn list_queued_peer_addresses(&self, since: i64) -> Result<Vec<(String, String)>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT peer.peer_id, address
+             FROM peer_address
+             JOIN peer ON peer.id = peer_address.peer_id
+             WHERE last_seen >= ?1
+               AND EXISTS (
+                 SELECT 1
+                 FROM message_outbox
+                 JOIN app ON app.id = to_app_id
+                 WHERE app.peer_id = peer.id
+                   AND delivered IS NULL AND failed IS NULL
+               )
+             ORDER BY last_seen DESC",
+        )?;
+        let rows = stmt.query_map([since], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        rows.collect()
+    }
+
+    /// Lists all of a peer's stored addresses, most recent first.
+    pub fn list_peer_address_info(&self, peer_id: &str) -> Result<Vec<PeerAddress>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT address, source, last_seen
+             FROM peer_address
+             JOIN peer ON peer.id = peer_address.peer_id
+             WHERE peer.peer_id = ?1
+             ORDER BY last_seen DESC",
+        )?;
+        let rows = stmt.query_map([peer_id], |row| {
+            Ok(PeerAddress {
+                address: row.get::<_, String>(0)?,
+                source: row.get::<_, AddressSource>(1)?,
+                last_seen: row.get::<_, i64>(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn delete_peer_addresses_before(&self, last_seen: i64) -> Result<usize> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM peer_address
+             WHERE last_seen < ?1",
+        )?;
+        stmt.execute([last_seen])
+    }
+
     pub fn get_message_data(&self, data: &[u8]) -> Result<Option<i64>> {
         let mut stmt = self.tx.prepare_cached(
             "SELECT id
@@ -283,73 +770,383 @@ impl<'a> StoreTransaction<'a> {
         self.put_message_data(data)
     }
 
+    /// Deletes message data no longer referenced by any inbox or
+    /// outbox entry.
     pub fn prune_message_data(&self) -> Result<()> {
-        // TODO: delete all messages without entry in inbox or outbox
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_data
+             WHERE id NOT IN (SELECT message_id FROM message_inbox)
+               AND id NOT IN (SELECT message_id FROM message_outbox)",
+        )?;
+        stmt.execute([])?;
         Ok(())
     }
 
-    pub fn put_message_outbox(&self, queued: i64, from: i64, to: i64, message_id: i64) -> Result<i64> {
+    pub fn put_message_outbox(&self, queued: i64, expires: i64, from: i64, to: i64, message_id: i64) -> Result<i64> {
         let mut stmt = self.tx.prepare_cached(
-            "INSERT INTO message_outbox (queued, from_app_id, to_app_id, message_id)
-             VALUES (?1, ?2, ?3, ?4)
+            "INSERT INTO message_outbox (queued, expires, from_app_id, to_app_id, message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)
              RETURNING id",
         )?;
-        stmt.query_row([queued, from, to, message_id], |row| row.get::<_, i64>(0))
+        stmt.query_row([queued, expires, from, to, message_id], |row| row.get::<_, i64>(0))
+    }
+
+    fn queued_message_from_row(row: &rusqlite::Row) -> Result<QueuedMessage> {
+        Ok(QueuedMessage {
+            id: row.get(0)?,
+            peer: row.get(1)?,
+            from_app_uuid: row.get(2)?,
+            to_app_uuid: row.get(3)?,
+            message: row.get(4)?,
+            attempts: row.get(5)?,
+            expires: row.get(6)?,
+        })
     }
 
-    pub fn delete_message_outbox(&self, outbox_id: i64) -> Result<()> {
+    /// Lists undelivered outbox messages which are due for another
+    /// delivery attempt (or have expired) at the given time.
+    pub fn list_due_outbox_messages(&self, now: i64) -> Result<Vec<QueuedMessage>> {
         let mut stmt = self.tx.prepare_cached(
-            "DELETE FROM message_outbox
+            "SELECT message_outbox.id, peer.peer_id, from_app.uuid, to_app.uuid, data, attempts, expires
+             FROM message_outbox
+             JOIN message_data ON message_data.id = message_id
+             JOIN app AS from_app ON from_app.id = from_app_id
+             JOIN app AS to_app ON to_app.id = to_app_id
+             JOIN peer ON peer.id = to_app.peer_id
+             WHERE delivered IS NULL AND failed IS NULL
+               AND (next_attempt <= ?1 OR expires <= ?1)
+             ORDER BY message_outbox.id ASC",
+        )?;
+        let rows = stmt.query_map([now], Self::queued_message_from_row)?;
+        rows.collect()
+    }
+
+    /// Lists all undelivered outbox messages addressed to a peer,
+    /// regardless of when their next delivery attempt is scheduled.
+    pub fn list_peer_outbox_messages(&self, peer_id: &str) -> Result<Vec<QueuedMessage>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT message_outbox.id, peer.peer_id, from_app.uuid, to_app.uuid, data, attempts, expires
+             FROM message_outbox
+             JOIN message_data ON message_data.id = message_id
+             JOIN app AS from_app ON from_app.id = from_app_id
+             JOIN app AS to_app ON to_app.id = to_app_id
+             JOIN peer ON peer.id = to_app.peer_id
+             WHERE delivered IS NULL AND failed IS NULL AND peer.peer_id = ?1
+             ORDER BY message_outbox.id ASC",
+        )?;
+        let rows = stmt.query_map([peer_id], Self::queued_message_from_row)?;
+        rows.collect()
+    }
+
+    pub fn record_outbox_attempt(&self, outbox_id: i64, next_attempt: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "UPDATE message_outbox
+             SET attempts = attempts + 1, next_attempt = ?2
+             WHERE id = ?1",
+        )?;
+        stmt.execute([outbox_id, next_attempt])?;
+        Ok(())
+    }
+
+    /// Records the reason for the most recent failed delivery attempt,
+    /// returning the number of attempts made so far.
+    pub fn record_outbox_error(&self, outbox_id: i64, kind: DeliveryError, error: &str) -> Result<Option<i64>> {
+        let mut stmt = self.tx.prepare_cached(
+            "UPDATE message_outbox
+             SET last_error = ?2, last_error_kind = ?3
+             WHERE id = ?1
+             RETURNING attempts",
+        )?;
+        stmt.query_row(params![outbox_id, error, kind.as_str()], |row| row.get::<_, i64>(0)).optional()
+    }
+
+    pub fn fail_message_outbox(&self, outbox_id: i64, failed: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "UPDATE message_outbox
+             SET failed = ?2
+             WHERE id = ?1",
+        )?;
+        stmt.execute([outbox_id, failed])?;
+        Ok(())
+    }
+
+    pub fn set_message_outbox_delivered(&self, outbox_id: i64, delivered: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "UPDATE message_outbox
+             SET delivered = ?2
              WHERE id = ?1",
         )?;
-        stmt.execute([outbox_id])?;
+        stmt.execute([outbox_id, delivered])?;
+        Ok(())
+    }
+
+    fn outbox_message_from_row(row: &rusqlite::Row) -> Result<OutboxMessage> {
+        let state = if row.get::<_, Option<i64>>(6)?.is_some() {
+            OutboxState::Delivered
+        } else if row.get::<_, Option<i64>>(7)?.is_some() {
+            OutboxState::Failed
+        } else {
+            OutboxState::Queued
+        };
+        Ok(OutboxMessage {
+            id: row.get::<_, usize>(0)?,
+            peer: row.get::<_, String>(1)?,
+            uuid: row.get::<_, String>(2)?,
+            message: row.get::<_, Vec<u8>>(3)?,
+            queued: row.get::<_, i64>(4)?,
+            attempts: row.get::<_, i64>(5)?,
+            state,
+            error: row.get::<_, Option<String>>(8)?,
+            error_kind: row.get::<_, Option<DeliveryError>>(9)?,
+        })
+    }
+
+    /// Returns an outbox message and the ID of the app that sent it.
+    pub fn get_outbox_message(&self, outbox_id: i64) -> Result<Option<(i64, OutboxMessage)>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT message_outbox.id, peer.peer_id, app.uuid, data, queued, attempts,
+                    delivered, failed, last_error, last_error_kind, from_app_id
+             FROM message_outbox
+             JOIN message_data ON message_data.id = message_id
+             JOIN app ON app.id = to_app_id
+             JOIN peer ON peer.id = app.peer_id
+             WHERE message_outbox.id = ?1",
+        )?;
+        stmt.query_row([outbox_id], |row| {
+            Ok((row.get::<_, i64>(10)?, Self::outbox_message_from_row(row)?))
+        }).optional()
+    }
+
+    pub fn list_app_outbox_messages(&self, from: i64) -> Result<Vec<OutboxMessage>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT message_outbox.id, peer.peer_id, app.uuid, data, queued, attempts,
+                    delivered, failed, last_error, last_error_kind
+             FROM message_outbox
+             JOIN message_data ON message_data.id = message_id
+             JOIN app ON app.id = to_app_id
+             JOIN peer ON peer.id = app.peer_id
+             WHERE from_app_id = ?1
+             ORDER BY message_outbox.id ASC",
+        )?;
+        let rows = stmt.query_map([from], Self::outbox_message_from_row)?;
+        rows.collect()
+    }
+
+    pub fn delete_outbox_message(&self, from: i64, outbox_id: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_outbox
+             WHERE from_app_id = ?1 AND id = ?2",
+        )?;
+        stmt.execute([from, outbox_id])?;
         self.prune_message_data()
     }
 
-    pub fn list_app_announcements(&self) -> std::result::Result<Vec<AppAnnouncement>, Box<dyn std::error::Error>> {
+    /// Lists announcements matching `filter` which haven't expired by
+    /// `now`, optionally only those received at or after `since`.
+    pub fn list_app_announcements(
+        &self,
+        now: i64,
+        filter: &AnnouncementFilter,
+        since: Option<i64>,
+    ) -> std::result::Result<Vec<AppAnnouncement>, Box<dyn std::error::Error>> {
         let mut stmt = self.tx.prepare_cached(
-            "SELECT peer.peer_id, uuid, data
+            "SELECT peer.peer_id, app.uuid, to_app.uuid, data, received, expires, signature, signed_at, verification
              FROM app_announcement
              JOIN app ON app.id = app_id
-             JOIN peer ON peer.id = app.peer_id",
+             JOIN peer ON peer.id = app.peer_id
+             LEFT JOIN app AS to_app ON to_app.id = to_app_id
+             WHERE expires > ?1
+             AND (?2 IS NULL OR peer.peer_id = ?2)
+             AND (?3 IS NULL OR app.uuid = ?3)
+             AND (to_app_id IS NULL OR to_app.uuid = ?4)
+             AND (?5 IS NULL OR received >= ?5)
+             ORDER BY received",
         )?;
-        let mut rows = stmt.query([])?;
+        let rows = stmt.query(params![now, filter.peer, filter.app_uuid, filter.to_app_uuid, since])?;
+        Self::collect_app_announcements(rows)
+    }
+
+    fn collect_app_announcements(mut rows: rusqlite::Rows) -> std::result::Result<Vec<AppAnnouncement>, Box<dyn std::error::Error>> {
         let mut results = Vec::new();
         while let Some(row) = rows.next()? {
-            let raw_data: String = row.get(2)?;
+            let raw_data: String = row.get(3)?;
             let data = serde_json::from_str(&raw_data)?;
             results.push(AppAnnouncement {
                 peer: row.get(0)?,
                 app_uuid: row.get(1)?,
+                to_app_uuid: row.get(2)?,
                 data,
+                received: row.get(4)?,
+                expires: row.get(5)?,
+                signature: row.get(6)?,
+                signed_at: row.get(7)?,
+                verification: row.get(8)?,
             });
         }
-        return Ok(results);
+        Ok(results)
+    }
+
+    /// Sets the expiry of announcements stored before expiry was
+    /// recorded to `max_ttl` seconds after they were received.
+    pub fn set_unknown_announcement_expiry(&self, max_ttl: i64) -> Result<usize> {
+        let mut stmt = self.tx.prepare_cached(
+            "UPDATE app_announcement
+             SET expires = received + ?1
+             WHERE expires IS NULL",
+        )?;
+        stmt.execute([max_ttl])
     }
 
     // Note: serde_json::Value used for data argument to enforce valid JSON in db
-    pub fn set_app_announcement(&self, app_id: i64, received: i64, data: &serde_json::Value) -> Result<()> {
+    pub fn set_app_announcement(
+        &self,
+        app_id: i64,
+        to_app_id: Option<i64>,
+        received: i64,
+        expires: i64,
+        data: &serde_json::Value,
+        signature: Option<&Signature>,
+    ) -> Result<()> {
         let mut stmt = self.tx.prepare_cached(
-            "INSERT INTO app_announcement (app_id, received, data)
-             VALUES (?1, ?2, ?3)
-             ON CONFLICT (app_id) DO UPDATE SET received=?2, data=?3",
+            "INSERT INTO app_announcement (app_id, to_app_id, received, expires, data, signature, signed_at, verification)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (app_id) DO UPDATE SET to_app_id=?2, received=?3, expires=?4, data=?5,
+                 signature=?6, signed_at=?7, verification=?8",
         )?;
-        stmt.execute(params![app_id, received, data.to_string()])?;
+        stmt.execute(params![
+            app_id,
+            to_app_id,
+            received,
+            expires,
+            data.to_string(),
+            signature.map(|s| &s.signature),
+            signature.map(|s| s.signed_at),
+            signature.map_or(Verification::Unsigned, |s| s.verification).as_str(),
+        ])?;
         Ok(())
     }
 
-    pub fn put_message_inbox(&self, received: i64, from: i64, to: i64, message_id: i64) -> Result<i64> {
+    /// Deletes an app's announcement, returning it if there was one.
+    pub fn take_app_announcement(&self, app_id: i64) -> std::result::Result<Option<AppAnnouncement>, Box<dyn std::error::Error>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT peer.peer_id, app.uuid, to_app.uuid, data, received, expires, signature, signed_at, verification
+             FROM app_announcement
+             JOIN app ON app.id = app_id
+             JOIN peer ON peer.id = app.peer_id
+             LEFT JOIN app AS to_app ON to_app.id = to_app_id
+             WHERE app_id = ?1",
+        )?;
+        let announcement = Self::collect_app_announcements(stmt.query([app_id])?)?.pop();
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM app_announcement WHERE app_id = ?1",
+        )?;
+        stmt.execute([app_id])?;
+        Ok(announcement)
+    }
+
+    /// Deletes announcements which expired by `now`, returning them.
+    pub fn take_expired_app_announcements(&self, now: i64) -> std::result::Result<Vec<AppAnnouncement>, Box<dyn std::error::Error>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT peer.peer_id, app.uuid, to_app.uuid, data, received, expires, signature, signed_at, verification
+             FROM app_announcement
+             JOIN app ON app.id = app_id
+             JOIN peer ON peer.id = app.peer_id
+             LEFT JOIN app AS to_app ON to_app.id = to_app_id
+             WHERE expires <= ?1",
+        )?;
+        let expired = Self::collect_app_announcements(stmt.query([now])?)?;
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM app_announcement WHERE expires <= ?1",
+        )?;
+        stmt.execute([now])?;
+        Ok(expired)
+    }
+
+    /// Returns every local app's broadcast announcement.
+    pub fn list_app_broadcasts(&self) -> std::result::Result<Vec<AppBroadcast>, Box<dyn std::error::Error>> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT uuid, data, ttl
+             FROM app_broadcast
+             JOIN app ON app.id = app_id",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut results = Vec::new();
+        while let Some(row) = rows.next()? {
+            let raw_data: String = row.get(1)?;
+            results.push(AppBroadcast {
+                app_uuid: row.get(0)?,
+                data: serde_json::from_str(&raw_data)?,
+                ttl: row.get(2)?,
+            });
+        }
+        Ok(results)
+    }
+
+    pub fn set_app_broadcast(&self, app_id: i64, updated: i64, ttl: Option<u64>, data: &serde_json::Value) -> Result<()> {
         let mut stmt = self.tx.prepare_cached(
-            "INSERT INTO message_inbox (received, from_app_id, to_app_id, message_id)
+            "INSERT INTO app_broadcast (app_id, updated, ttl, data)
              VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (app_id) DO UPDATE SET updated=?2, ttl=?3, data=?4",
+        )?;
+        stmt.execute(params![app_id, updated, ttl, data.to_string()])?;
+        Ok(())
+    }
+
+    pub fn delete_app_broadcast(&self, app_id: i64) -> Result<()> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM app_broadcast WHERE app_id = ?1",
+        )?;
+        stmt.execute([app_id])?;
+        Ok(())
+    }
+
+    pub fn put_message_inbox(
+        &self,
+        received: i64,
+        from: i64,
+        to: i64,
+        message_id: i64,
+        signature: Option<&Signature>,
+    ) -> Result<i64> {
+        let mut stmt = self.tx.prepare_cached(
+            "INSERT INTO message_inbox (received, from_app_id, to_app_id, message_id, signature, signed_at, verification)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
              RETURNING id",
         )?;
-        stmt.query_row([received, from, to, message_id], |row| row.get::<_, i64>(0))
+        stmt.query_row(params![
+            received,
+            from,
+            to,
+            message_id,
+            signature.map(|s| &s.signature),
+            signature.map(|s| s.signed_at),
+            signature.map_or(Verification::Unsigned, |s| s.verification).as_str(),
+        ], |row| row.get::<_, i64>(0))
+    }
+
+    pub fn count_app_inbox_messages(&self, to: i64) -> Result<i64> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT COUNT(*)
+             FROM message_inbox
+             WHERE to_app_id = ?1",
+        )?;
+        stmt.query_row([to], |row| row.get::<_, i64>(0))
+    }
+
+    /// Counts messages in an app's inbox sent by any app on the given peer.
+    pub fn count_app_inbox_messages_from_peer(&self, to: i64, peer_id: i64) -> Result<i64> {
+        let mut stmt = self.tx.prepare_cached(
+            "SELECT COUNT(*)
+             FROM message_inbox
+             JOIN app ON app.id = from_app_id
+             WHERE to_app_id = ?1 AND app.peer_id = ?2",
+        )?;
+        stmt.query_row([to, peer_id], |row| row.get::<_, i64>(0))
     }
 
     pub fn list_app_inbox_messages(&self, app_id: i64) -> Result<Vec<Message>> {
         let mut stmt = self.tx.prepare_cached(
-            "SELECT message_inbox.id, peer.peer_id, app.uuid, data
+            "SELECT message_inbox.id, peer.peer_id, app.uuid, data, signature, signed_at, verification
              FROM message_inbox
              JOIN message_data ON message_data.id = message_id
              JOIN app ON app.id = from_app_id
@@ -365,9 +1162,12 @@ impl<'a> StoreTransaction<'a> {
                 peer: row.get::<_, String>(1)?,
                 uuid: row.get::<_, String>(2)?,
                 message: row.get::<_, Vec<u8>>(3)?,
+                signature: row.get(4)?,
+                signed_at: row.get(5)?,
+                verification: row.get(6)?,
             });
         }
-        return Ok(results);
+        Ok(results)
     }
 
     pub fn delete_inbox_message(&self, to: i64, message_id: i64) -> Result<()> {
@@ -376,7 +1176,32 @@ impl<'a> StoreTransaction<'a> {
              WHERE to_app_id = ?1 AND id = ?2",
         )?;
         stmt.execute([to, message_id])?;
-        Ok(())
+        self.prune_message_data()
+    }
+
+    /// Deletes all inbox messages received before the given time,
+    /// returning the number of messages deleted.
+    pub fn delete_inbox_messages_before(&self, received: i64) -> Result<usize> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_inbox
+             WHERE received < ?1",
+        )?;
+        let deleted = stmt.execute([received])?;
+        self.prune_message_data()?;
+        Ok(deleted)
+    }
+
+    /// Deletes all outbox messages queued before the given time,
+    /// regardless of delivery state, returning the number of messages
+    /// deleted.
+    pub fn delete_outbox_messages_before(&self, queued: i64) -> Result<usize> {
+        let mut stmt = self.tx.prepare_cached(
+            "DELETE FROM message_outbox
+             WHERE queued < ?1",
+        )?;
+        let deleted = stmt.execute([queued])?;
+        self.prune_message_data()?;
+        Ok(deleted)
     }
 
     pub fn commit(self) -> Result<()> {
@@ -384,3 +1209,379 @@ impl<'a> StoreTransaction<'a> {
         tx.commit()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store() -> Store {
+        let mut store = Store::new(Connection::open_in_memory().unwrap());
+        let tx = store.transaction().unwrap();
+        tx.migrate().unwrap();
+        tx.commit().unwrap();
+        store
+    }
+
+    #[test]
+    fn list_due_outbox_messages_respects_next_attempt_and_expiry() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote = tx.put_peer("remote").unwrap();
+        let from = tx.put_app(local, "from-uuid").unwrap();
+        let to = tx.put_app(remote, "to-uuid").unwrap();
+        let data = tx.put_message_data(b"hello").unwrap();
+        let outbox_id = tx.put_message_outbox(100, 1000, from, to, data).unwrap();
+
+        let due = tx.list_due_outbox_messages(100).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, outbox_id);
+        assert_eq!(due[0].peer, "remote");
+        assert_eq!(due[0].message, b"hello");
+        assert_eq!(due[0].attempts, 0);
+
+        // Not due again until after backoff
+        tx.record_outbox_attempt(outbox_id, 200).unwrap();
+        assert!(tx.list_due_outbox_messages(150).unwrap().is_empty());
+        assert_eq!(tx.list_due_outbox_messages(200).unwrap()[0].attempts, 1);
+
+        // Still listed for the peer regardless of backoff
+        assert_eq!(tx.list_peer_outbox_messages("remote").unwrap().len(), 1);
+
+        assert_eq!(tx.record_outbox_error(outbox_id, DeliveryError::Unreachable, "Timeout").unwrap(), Some(1));
+        assert_eq!(tx.record_outbox_error(outbox_id + 1, DeliveryError::Unreachable, "Timeout").unwrap(), None);
+
+        // Failed messages are no longer listed
+        tx.fail_message_outbox(outbox_id, 1000).unwrap();
+        assert!(tx.list_due_outbox_messages(1000).unwrap().is_empty());
+        assert!(tx.list_peer_outbox_messages("remote").unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_message_data_keeps_referenced_data() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote = tx.put_peer("remote").unwrap();
+        let local_app = tx.put_app(local, "local-uuid").unwrap();
+        let remote_app = tx.put_app(remote, "remote-uuid").unwrap();
+        let sent = tx.put_message_data(b"sent").unwrap();
+        let received = tx.put_message_data(b"received").unwrap();
+        let shared = tx.put_message_data(b"shared").unwrap();
+        tx.put_message_data(b"orphan").unwrap();
+        let outbox_id = tx.put_message_outbox(100, 1000, local_app, remote_app, sent).unwrap();
+        tx.put_message_outbox(100, 1000, local_app, remote_app, shared).unwrap();
+        let inbox_id = tx.put_message_inbox(100, remote_app, local_app, received, None).unwrap();
+        tx.put_message_inbox(100, remote_app, local_app, shared, None).unwrap();
+
+        tx.prune_message_data().unwrap();
+        assert_eq!(tx.get_message_data(b"orphan").unwrap(), None);
+        assert_eq!(tx.get_message_data(b"sent").unwrap(), Some(sent));
+
+        tx.delete_outbox_message(local_app, outbox_id).unwrap();
+        assert_eq!(tx.get_message_data(b"sent").unwrap(), None);
+
+        tx.delete_inbox_message(local_app, inbox_id).unwrap();
+        assert_eq!(tx.get_message_data(b"received").unwrap(), None);
+        assert_eq!(tx.get_message_data(b"shared").unwrap(), Some(shared));
+        tx.commit().unwrap();
+
+        store.compact().unwrap();
+    }
+
+    #[test]
+    fn message_allow_list_only_applies_when_restricted() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote = tx.put_peer("remote").unwrap();
+        let to = tx.put_app(local, "local-uuid").unwrap();
+        let friend = tx.put_app(remote, "friend").unwrap();
+        let stranger = tx.put_app(remote, "stranger").unwrap();
+
+        assert!(tx.is_message_allowed(stranger, to).unwrap());
+        assert!(!tx.has_message_allow(friend, to).unwrap());
+        tx.put_message_allow(friend, to).unwrap();
+        assert!(tx.has_message_allow(friend, to).unwrap());
+        tx.put_message_allow(friend, to).unwrap();
+        tx.set_app_messages_restricted(to, true).unwrap();
+        assert!(tx.is_message_allowed(friend, to).unwrap());
+        assert!(!tx.is_message_allowed(stranger, to).unwrap());
+        assert_eq!(tx.list_message_allow(to).unwrap(), vec![MessageAllow {
+            peer: String::from("remote"),
+            app_uuid: String::from("friend"),
+        }]);
+
+        tx.delete_message_allow(friend, to).unwrap();
+        assert!(!tx.is_message_allowed(friend, to).unwrap());
+        assert!(tx.list_message_allow(to).unwrap().is_empty());
+
+        tx.set_app_messages_restricted(to, false).unwrap();
+        assert!(tx.is_message_allowed(stranger, to).unwrap());
+    }
+
+    #[test]
+    fn message_invites() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote = tx.put_peer("remote").unwrap();
+        let to = tx.put_app(local, "local-uuid").unwrap();
+        let from = tx.put_app(remote, "remote-uuid").unwrap();
+
+        let first = tx.put_message_invite(100, from, to, &serde_json::json!({"nick": "a"})).unwrap();
+        let second = tx.put_message_invite(200, from, to, &serde_json::json!({"nick": "b"})).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(tx.count_peer_message_invites(remote).unwrap(), 1);
+        let invites = tx.list_app_message_invites(to).unwrap();
+        assert_eq!(invites, vec![Invite {
+            id: first as usize,
+            peer: String::from("remote"),
+            app_uuid: String::from("remote-uuid"),
+            data: serde_json::json!({"nick": "b"}),
+        }]);
+
+        assert_eq!(tx.take_message_invite(from, first).unwrap(), None);
+        assert_eq!(tx.take_message_invite(to, first).unwrap(), Some(from));
+        assert!(tx.list_app_message_invites(to).unwrap().is_empty());
+
+        tx.put_message_invite_sent(100, to, from).unwrap();
+        assert!(!tx.take_message_invite_sent(from, to).unwrap());
+        assert!(tx.take_message_invite_sent(to, from).unwrap());
+        assert!(!tx.take_message_invite_sent(to, from).unwrap());
+    }
+
+    #[test]
+    fn inbox_quotas_and_expiry() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote1 = tx.put_peer("remote1").unwrap();
+        let remote2 = tx.put_peer("remote2").unwrap();
+        let to = tx.put_app(local, "local-uuid").unwrap();
+        let from1a = tx.put_app(remote1, "remote1-a").unwrap();
+        let from1b = tx.put_app(remote1, "remote1-b").unwrap();
+        let from2 = tx.put_app(remote2, "remote2").unwrap();
+        let data = tx.put_message_data(b"hello").unwrap();
+        tx.put_message_inbox(100, from1a, to, data, None).unwrap();
+        tx.put_message_inbox(200, from1b, to, data, None).unwrap();
+        tx.put_message_inbox(300, from2, to, data, None).unwrap();
+
+        assert_eq!(tx.count_app_inbox_messages(to).unwrap(), 3);
+        assert_eq!(tx.count_app_inbox_messages_from_peer(to, remote1).unwrap(), 2);
+        assert_eq!(tx.count_app_inbox_messages_from_peer(to, remote2).unwrap(), 1);
+
+        assert_eq!(tx.get_inbox_quota(to).unwrap(), (None, None));
+        tx.set_inbox_quota(to, Some(10), None).unwrap();
+        assert_eq!(tx.get_inbox_quota(to).unwrap(), (Some(10), None));
+        tx.set_inbox_quota(to, None, Some(5)).unwrap();
+        assert_eq!(tx.get_inbox_quota(to).unwrap(), (None, Some(5)));
+
+        assert_eq!(tx.delete_inbox_messages_before(250).unwrap(), 2);
+        assert_eq!(tx.count_app_inbox_messages(to).unwrap(), 1);
+        assert_eq!(tx.get_message_data(b"hello").unwrap(), Some(data));
+        assert_eq!(tx.delete_inbox_messages_before(1000).unwrap(), 1);
+        assert_eq!(tx.get_message_data(b"hello").unwrap(), None);
+    }
+
+    #[test]
+    fn list_app_outbox_messages_reports_delivery_state() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let remote = tx.put_peer("remote").unwrap();
+        let from = tx.put_app(local, "from-uuid").unwrap();
+        let to = tx.put_app(remote, "to-uuid").unwrap();
+        let data = tx.put_message_data(b"hello").unwrap();
+        let queued = tx.put_message_outbox(100, 1000, from, to, data).unwrap();
+        let delivered = tx.put_message_outbox(100, 1000, from, to, data).unwrap();
+        let failed = tx.put_message_outbox(100, 1000, from, to, data).unwrap();
+        tx.set_message_outbox_delivered(delivered, 200).unwrap();
+        tx.record_outbox_error(failed, DeliveryError::Unreachable, "Timeout").unwrap();
+        tx.fail_message_outbox(failed, 200).unwrap();
+
+        let messages = tx.list_app_outbox_messages(from).unwrap();
+        let states: Vec<(usize, OutboxState)> = messages.iter()
+            .map(|m| (m.id, m.state.clone()))
+            .collect();
+        assert_eq!(states, vec![
+            (queued as usize, OutboxState::Queued),
+            (delivered as usize, OutboxState::Delivered),
+            (failed as usize, OutboxState::Failed),
+        ]);
+        assert_eq!(messages[2].error, Some(String::from("Timeout")));
+        assert_eq!(messages[2].error_kind, Some(DeliveryError::Unreachable));
+        assert_eq!(messages[0].error_kind, None);
+        assert_eq!(messages[0].peer, "remote");
+        assert_eq!(messages[0].uuid, "to-uuid");
+
+        // Delivered messages are not retried
+        assert_eq!(tx.list_peer_outbox_messages("remote").unwrap().len(), 1);
+
+        let (app_id, message) = tx.get_outbox_message(delivered).unwrap().unwrap();
+        assert_eq!(app_id, from);
+        assert_eq!(message.state, OutboxState::Delivered);
+
+        // Only the sending app may delete its outbox messages
+        tx.delete_outbox_message(to, queued).unwrap();
+        assert_eq!(tx.list_app_outbox_messages(from).unwrap().len(), 3);
+        tx.delete_outbox_message(from, queued).unwrap();
+        assert_eq!(tx.list_app_outbox_messages(from).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn peer_addresses_are_updated_and_expire() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let peer = tx.put_peer("remote").unwrap();
+        tx.put_peer_address(peer, "/ip4/192.168.1.2/tcp/4001", AddressSource::Mdns, 100).unwrap();
+        tx.put_peer_address(peer, "/ip4/203.0.113.1/tcp/4001", AddressSource::Identify, 200).unwrap();
+        assert_eq!(tx.list_peer_addresses("remote", 0).unwrap(), vec![
+            String::from("/ip4/203.0.113.1/tcp/4001"),
+            String::from("/ip4/192.168.1.2/tcp/4001"),
+        ]);
+        assert_eq!(tx.list_peer_addresses("remote", 150).unwrap().len(), 1);
+
+        // Seeing an address again refreshes it
+        tx.put_peer_address(peer, "/ip4/192.168.1.2/tcp/4001", AddressSource::Dial, 300).unwrap();
+        assert_eq!(tx.list_peer_addresses("remote", 250).unwrap(), vec![
+            String::from("/ip4/192.168.1.2/tcp/4001"),
+        ]);
+
+        let info = tx.list_peer_address_info("remote").unwrap();
+        assert_eq!(info[0], PeerAddress {
+            address: String::from("/ip4/192.168.1.2/tcp/4001"),
+            source: AddressSource::Mdns,
+            last_seen: 300,
+        });
+        assert_eq!(info[1].source, AddressSource::Identify);
+
+        assert_eq!(tx.delete_peer_addresses_before(250).unwrap(), 1);
+        assert_eq!(tx.list_peer_addresses("remote", 0).unwrap().len(), 1);
+        assert!(tx.list_peer_addresses("unknown", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn queued_peer_addresses_are_listed() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let alice = tx.put_peer("alice").unwrap();
+        let bob = tx.put_peer("bob").unwrap();
+        let from = tx.put_app(local, "from-uuid").unwrap();
+        let alice_app = tx.put_app(alice, "alice-uuid").unwrap();
+        let bob_app = tx.put_app(bob, "bob-uuid").unwrap();
+        tx.put_peer_address(alice, "/ip4/192.168.1.2/tcp/4001", AddressSource::Dial, 100).unwrap();
+        tx.put_peer_address(bob, "/ip4/192.168.1.3/tcp/4001", AddressSource::Mdns, 200).unwrap();
+        tx.put_peer_address(alice, "/ip4/203.0.113.1/tcp/4001", AddressSource::Identify, 300).unwrap();
+        // No queued messages
+        assert!(tx.list_queued_peer_addresses(0).unwrap().is_empty());
+
+        let data = tx.put_message_data(b"hello").unwrap();
+        let to_alice = tx.put_message_outbox(100, 1000, from, alice_app, data).unwrap();
+        let to_bob = tx.put_message_outbox(100, 1000, from, bob_app, data).unwrap();
+        assert_eq!(tx.list_queued_peer_addresses(150).unwrap(), vec![
+            (String::from("alice"), String::from("/ip4/203.0.113.1/tcp/4001")),
+            (String::from("bob"), String::from("/ip4/192.168.1.3/tcp/4001")),
+        ]);
+        assert_eq!(tx.list_queued_peer_addresses(0).unwrap().len(), 3);
+
+        // Delivered and failed messages are no longer queued
+        tx.set_message_outbox_delivered(to_alice, 200).unwrap();
+        tx.fail_message_outbox(to_bob, 200).unwrap();
+        assert!(tx.list_queued_peer_addresses(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn app_broadcasts_are_replaced() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let app = tx.put_app(local, "app-uuid").unwrap();
+        assert!(tx.list_app_broadcasts().unwrap().is_empty());
+        tx.set_app_broadcast(app, 100, None, &serde_json::json!({"nick": "alice"})).unwrap();
+        tx.set_app_broadcast(app, 200, Some(60), &serde_json::json!({"nick": "bob"})).unwrap();
+        assert_eq!(tx.list_app_broadcasts().unwrap(), vec![AppBroadcast {
+            app_uuid: String::from("app-uuid"),
+            data: serde_json::json!({"nick": "bob"}),
+            ttl: Some(60),
+        }]);
+        tx.delete_app_broadcast(app).unwrap();
+        assert!(tx.list_app_broadcasts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn app_announcements_expire_and_filter() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let local = tx.put_peer("local").unwrap();
+        let alice = tx.put_peer("alice").unwrap();
+        let bob = tx.put_peer("bob").unwrap();
+        let chat = tx.put_app(local, "chat").unwrap();
+        let alice_app = tx.put_app(alice, "alice-app").unwrap();
+        let bob_app = tx.put_app(bob, "bob-app").unwrap();
+        tx.set_app_announcement(alice_app, None, 100, 200, &serde_json::json!("a"), None).unwrap();
+        let signature = Signature {
+            signature: b"sig".to_vec(),
+            signed_at: 140,
+            verification: Verification::Unverifiable,
+        };
+        tx.set_app_announcement(bob_app, Some(chat), 150, 300, &serde_json::json!("b"), Some(&signature)).unwrap();
+
+        let list = |now, peer: Option<&str>, app_uuid: Option<&str>, to_app_uuid: Option<&str>, since| -> Vec<String> {
+            let filter = AnnouncementFilter {
+                peer: peer.map(String::from),
+                app_uuid: app_uuid.map(String::from),
+                to_app_uuid: to_app_uuid.map(String::from),
+            };
+            tx.list_app_announcements(now, &filter, since).unwrap()
+                .into_iter()
+                .map(|a| a.app_uuid)
+                .collect()
+        };
+        assert_eq!(list(100, None, None, Some("chat"), None), vec!["alice-app", "bob-app"]);
+        assert_eq!(list(100, Some("bob"), None, Some("chat"), None), vec!["bob-app"]);
+        assert_eq!(list(100, None, Some("alice-app"), Some("chat"), None), vec!["alice-app"]);
+        assert_eq!(list(100, None, None, Some("chat"), Some(120)), vec!["bob-app"]);
+        // Announcements sent to an app are hidden unless it is named
+        assert_eq!(list(100, None, None, None, None), vec!["alice-app"]);
+        assert_eq!(list(100, None, None, Some("other"), None), vec!["alice-app"]);
+        // Expired announcements are hidden before they are deleted
+        assert_eq!(list(200, None, None, Some("chat"), None), vec!["bob-app"]);
+
+        let expired = tx.take_expired_app_announcements(200).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].peer, "alice");
+        assert_eq!(expired[0].verification, Verification::Unsigned);
+        assert_eq!(expired[0].signed_at, None);
+        let taken = tx.take_app_announcement(bob_app).unwrap().unwrap();
+        assert_eq!(taken.to_app_uuid.as_deref(), Some("chat"));
+        assert_eq!(taken.data, serde_json::json!("b"));
+        assert_eq!(taken.signature.as_deref(), Some(&b"sig"[..]));
+        assert_eq!(taken.signed_at, Some(140));
+        assert_eq!(taken.verification, Verification::Unverifiable);
+        assert!(tx.take_app_announcement(bob_app).unwrap().is_none());
+        assert!(list(0, None, None, None, None).is_empty());
+    }
+
+    #[test]
+    fn unknown_announcement_expiry_uses_max_ttl() {
+        let mut store = test_store();
+        let tx = store.transaction().unwrap();
+        let alice = tx.put_peer("alice").unwrap();
+        let alice_app = tx.put_app(alice, "alice-app").unwrap();
+        let bob = tx.put_peer("bob").unwrap();
+        let bob_app = tx.put_app(bob, "bob-app").unwrap();
+        tx.set_app_announcement(alice_app, None, 100, 200, &serde_json::json!("a"), None).unwrap();
+        // As left by the migration adding expiry
+        tx.tx.execute(
+            "INSERT INTO app_announcement (app_id, received, data) VALUES (?1, 150, '\"b\"')",
+            [bob_app],
+        ).unwrap();
+        assert_eq!(tx.set_unknown_announcement_expiry(60).unwrap(), 1);
+        let expired = tx.take_expired_app_announcements(210).unwrap();
+        assert_eq!(expired.len(), 2);
+        assert_eq!(expired[1].app_uuid, "bob-app");
+        assert_eq!(expired[1].expires, 210);
+    }
+}
@@ -1,38 +1,242 @@
-use libp2p::{identity::Keypair, identify, mdns, request_response::{self, ProtocolSupport}, swarm::{NetworkBehaviour, StreamProtocol}};
+use libp2p::{identity::Keypair, gossipsub, identify, kad, mdns, request_response::{self, ProtocolSupport}, swarm::{NetworkBehaviour, StreamProtocol, behaviour::toggle::Toggle, dial_opts::DialOpts}, multiaddr::Protocol, Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use std::error::Error;
+use std::fmt;
+
+use crate::config::NetworkSettings;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Announce {
         app_uuid: String,
+        /// Only shows the announcement to this app on the recipient
+        #[serde(default)]
+        to_app_uuid: Option<String>,
         data: serde_json::Value,
+        /// Seconds until the announcement expires, capped by the
+        /// recipient. Absent from older peers.
+        #[serde(default)]
+        ttl: Option<u64>,
+        /// Sender's signature over the announcement. Absent from older
+        /// peers.
+        #[serde(default)]
+        signature: Option<Vec<u8>>,
+        /// When the sender signed the announcement
+        #[serde(default)]
+        signed_at: Option<i64>,
+    },
+    /// Asks the recipient to forget an app's announcement
+    Withdraw {
+        app_uuid: String,
     },
     Message {
         from_app_uuid: String,
         to_app_uuid: String,
         message: Vec<u8>,
+        /// Sender's signature over the message. Absent from older peers.
+        #[serde(default)]
+        signature: Option<Vec<u8>>,
+        /// When the sender signed the message
+        #[serde(default)]
+        signed_at: Option<i64>,
+    },
+    Invite {
+        from_app_uuid: String,
+        to_app_uuid: String,
+        data: serde_json::Value,
+    },
+    InviteResponse {
+        from_app_uuid: String,
+        to_app_uuid: String,
+        accepted: bool,
     },
 }
 
+/// A request as sent over the network. Requests which fail to decode,
+/// e.g. new request types from a newer version, are received as
+/// `Unsupported` so the sender can be told instead of the stream failing.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum WireRequest {
+    Supported(Request),
+    #[serde(skip_serializing)]
+    Unsupported(serde::de::IgnoredAny),
+}
+
+impl From<Request> for WireRequest {
+    fn from(request: Request) -> Self {
+        WireRequest::Supported(request)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Acknowledge,
+    /// The receiving app's inbox is full, the sender may retry later.
+    QuotaExceeded,
+    /// The receiving app does not accept messages from the sending app.
+    NotAllowed,
+    /// The receiving peer does not know the given app, either the
+    /// receiving app or the sending app.
+    UnknownApp {
+        app_uuid: String,
+    },
+    /// The receiving peer does not understand the request.
+    UnsupportedVersion,
+    /// The receiving peer failed to handle the request. The message is
+    /// generic, details are only logged by the receiving peer.
+    InternalError {
+        message: String,
+    },
+}
+
+/// Kademlia protocol name, kept separate from the public IPFS DHT so
+/// the routing table only contains mutiny peers.
+pub const KADEMLIA_PROTOCOL: StreamProtocol = StreamProtocol::new("/mutiny/kad/1.0.0");
+
+/// Returns the gossipsub topic for an app's topic. Topics are namespaced
+/// by the peer and UUID of the app publishing to them, so subscribers
+/// never see messages meant for another app's topic of the same name.
+/// Messages from any other peer must be dropped on receipt, as nothing
+/// stops a peer publishing to any topic.
+pub fn app_topic(peer: &PeerId, app_uuid: &str, topic: &str) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("/mutiny/app/{peer}/{app_uuid}/{topic}"))
 }
 
-// A custom network behaviour that combines Request/Response and MDNS.
+/// Splits a gossipsub topic into its peer ID, app UUID and topic name.
+pub fn parse_app_topic(hash: &gossipsub::TopicHash) -> Option<(&str, &str, &str)> {
+    let (peer, rest) = hash.as_str().strip_prefix("/mutiny/app/")?.split_once('/')?;
+    let (app_uuid, topic) = rest.split_once('/')?;
+    Some((peer, app_uuid, topic))
+}
+
+// A custom network behaviour that combines Request/Response, Gossipsub, Kademlia and MDNS.
 #[derive(NetworkBehaviour)]
 pub struct MutinyBehaviour {
-    pub request_response: request_response::cbor::Behaviour<Request, Response>,
+    pub request_response: request_response::cbor::Behaviour<WireRequest, Response>,
+    pub gossipsub: gossipsub::Behaviour,
     pub identify: identify::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    /// mDNS only supports a single IP version per behaviour, this is
+    /// also disabled if the host does not support IPv6 multicast.
+    pub mdns_ipv6: Toggle<mdns::tokio::Behaviour>,
 }
 
 pub type Swarm = libp2p::swarm::Swarm<MutinyBehaviour>;
-pub type Message = request_response::Message<Request, Response>;
+pub type Message = request_response::Message<WireRequest, Response>;
+
+/// Transports the swarm can listen on and dial.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Quic,
+}
+
+impl Transport {
+    /// Returns the transport an address uses, if it is one of these.
+    pub fn of(addr: &Multiaddr) -> Option<Transport> {
+        let mut transport = None;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::Tcp(_) => transport = Some(Transport::Tcp),
+                Protocol::QuicV1 => transport = Some(Transport::Quic),
+                Protocol::Ws(_) | Protocol::Wss(_) => transport = None,
+                _ => {},
+            }
+        }
+        transport
+    }
+
+    fn ipv4_listen_address(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "/ip4/0.0.0.0/tcp/0",
+            Transport::Quic => "/ip4/0.0.0.0/udp/0/quic-v1",
+        }
+    }
 
-pub async fn start(keypair: Keypair) -> Result<
+    fn ipv6_listen_address(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "/ip6/::/tcp/0",
+            Transport::Quic => "/ip6/::/udp/0/quic-v1",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Tcp => "tcp",
+            Transport::Quic => "quic",
+        })
+    }
+}
+
+/// Normalizes a peer's address for storage and dialing, returning None
+/// if it could never be dialed. IPv4-mapped IPv6 addresses (as reported
+/// by dual-stack sockets) are converted to plain IPv4 and any trailing
+/// /p2p/ peer ID is removed so the same address isn't recorded twice.
+pub fn normalize_address(addr: Multiaddr) -> Option<Multiaddr> {
+    let addr = match split_peer_id(addr.clone()) {
+        Some((_, addr)) => addr,
+        None => addr,
+    };
+    let mut protocols = addr.iter();
+    let first = match protocols.next()? {
+        Protocol::Ip4(ip) if ip.is_unspecified() => return None,
+        Protocol::Ip6(ip) if ip.is_unspecified() => return None,
+        // Link-local addresses need a zone index, which multiaddrs can't express
+        Protocol::Ip6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => return None,
+        Protocol::Ip6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => Protocol::Ip4(ipv4),
+            None => Protocol::Ip6(ip),
+        },
+        other => other,
+    };
+    Some(std::iter::once(first).chain(protocols).collect())
+}
+
+/// Splits the trailing /p2p/<peer id> from an address.
+pub fn split_peer_id(mut addr: Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    match addr.pop()? {
+        Protocol::P2p(peer_id) => Some((peer_id, addr)),
+        _ => None,
+    }
+}
+
+/// Orders addresses to dial by the preferred transports, earliest
+/// first, dropping addresses which use any other transport.
+pub fn prefer_transports(addrs: impl IntoIterator<Item = Multiaddr>, preference: &[Transport]) -> Vec<Multiaddr> {
+    let mut ranked: Vec<(usize, Multiaddr)> = addrs.into_iter()
+        .filter_map(|addr| {
+            let transport = Transport::of(&addr)?;
+            let rank = preference.iter().position(|preferred| *preferred == transport)?;
+            Some((rank, addr))
+        })
+        .collect();
+    ranked.sort_by_key(|(rank, _)| *rank);
+    ranked.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Dials a peer using those of its addresses which use one of the
+/// `preference` transports, most preferred first.
+pub fn dial_peer(swarm: &mut Swarm, peer_id: PeerId, addrs: Vec<Multiaddr>, preference: &[Transport]) {
+    let addrs = prefer_transports(addrs, preference);
+    if addrs.is_empty() {
+        return;
+    }
+    let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+    if let Err(err) = swarm.dial(opts) {
+        eprintln!("Error dialing {}: {}", peer_id, err);
+    }
+}
+
+/// Starts the swarm listening on each of the configured `listen`
+/// addresses, or on a random port for each of `transports` if no
+/// addresses are given.
+pub async fn start(keypair: Keypair, settings: &NetworkSettings) -> Result<
     libp2p::swarm::Swarm<MutinyBehaviour>,
     Box<dyn Error>,
 > {
@@ -43,26 +247,194 @@ pub async fn start(keypair: Keypair) -> Result<
             libp2p::noise::Config::new,
             libp2p::yamux::Config::default,
         )?
+        .with_quic()
         .with_behaviour(|key| {
-            let request_response = libp2p::request_response::cbor::Behaviour::<Request, Response>::new(
+            let request_response = libp2p::request_response::cbor::Behaviour::<WireRequest, Response>::new(
                 [(StreamProtocol::new("/mutiny-request-response-protocol"), ProtocolSupport::Full)],
                 libp2p::request_response::Config::default(),
             );
+            // Messages are signed so the publishing peer can't be spoofed
+            let gossipsub = gossipsub::Behaviour::new(
+                gossipsub::MessageAuthenticity::Signed(key.clone()),
+                gossipsub::Config::default(),
+            )?;
             let identify = identify::Behaviour::new(identify::Config::new(
                 String::from("mutiny/1.0.0"),
                 key.public(),
             ));
+            // Find peers beyond the local network using the DHT
+            let mut kademlia_config = kad::Config::default();
+            kademlia_config.set_protocol_names(vec![KADEMLIA_PROTOCOL]);
+            let kademlia = kad::Behaviour::with_config(
+                key.public().to_peer_id(),
+                kad::store::MemoryStore::new(key.public().to_peer_id()),
+                kademlia_config,
+            );
             // Find peers on local network using multicast DNS
-            let mdns = libp2p::mdns::tokio::Behaviour::new(
-                libp2p::mdns::Config::default(), key.public().to_peer_id()
-            )?;
-            Ok(MutinyBehaviour { request_response, identify, mdns })
+            let mut mdns = None;
+            let mut mdns_ipv6 = None;
+            if settings.mdns {
+                mdns = Some(libp2p::mdns::tokio::Behaviour::new(
+                    libp2p::mdns::Config::default(), key.public().to_peer_id()
+                )?);
+                mdns_ipv6 = match libp2p::mdns::tokio::Behaviour::new(
+                    libp2p::mdns::Config { enable_ipv6: true, ..Default::default() },
+                    key.public().to_peer_id(),
+                ) {
+                    Ok(behaviour) => Some(behaviour),
+                    Err(err) => {
+                        eprintln!("IPv6 mDNS unavailable: {}", err);
+                        None
+                    },
+                };
+            }
+            Ok(MutinyBehaviour {
+                request_response,
+                gossipsub,
+                identify,
+                kademlia,
+                mdns: Toggle::from(mdns),
+                mdns_ipv6: Toggle::from(mdns_ipv6),
+            })
         })?
         .with_swarm_config(
-            |c| c.with_idle_connection_timeout(Duration::from_secs(60))
+            |c| c.with_idle_connection_timeout(Duration::from_secs(settings.idle_connection_timeout_secs))
         )
         .build();
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // Peers rarely have a confirmed external address, which Kademlia
+    // otherwise requires before answering queries from other peers
+    swarm.behaviour_mut().kademlia.set_mode(Some(kad::Mode::Server));
+    for addr in &settings.bootstrap {
+        let (peer_id, addr) = split_peer_id(addr.clone()).ok_or("Bootstrap address missing /p2p/ peer ID")?;
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+    }
+
+    for addr in &settings.listen {
+        swarm.listen_on(addr.clone())?;
+    }
+    if !settings.listen.is_empty() {
+        return Ok(swarm);
+    }
+    for transport in &settings.transports {
+        swarm.listen_on(transport.ipv4_listen_address().parse()?)?;
+        // Hosts without IPv6 support can still run IPv4-only
+        if let Err(err) = swarm.listen_on(transport.ipv6_listen_address().parse()?) {
+            eprintln!("Failed to listen on {}: {}", transport.ipv6_listen_address(), err);
+        }
+    }
     Ok(swarm)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_unsupported_requests() {
+        let encoded = cbor4ii::serde::to_vec(Vec::new(), &WireRequest::from(Request::InviteResponse {
+            from_app_uuid: String::from("from"),
+            to_app_uuid: String::from("to"),
+            accepted: true,
+        })).unwrap();
+        let decoded: WireRequest = cbor4ii::serde::from_slice(&encoded).unwrap();
+        assert!(matches!(decoded, WireRequest::Supported(Request::InviteResponse { accepted: true, .. })));
+
+        // A request type added in a later version
+        #[derive(Serialize)]
+        enum FutureRequest {
+            Poll { question: String },
+        }
+        let encoded = cbor4ii::serde::to_vec(Vec::new(), &FutureRequest::Poll {
+            question: String::from("?"),
+        }).unwrap();
+        let decoded: WireRequest = cbor4ii::serde::from_slice(&encoded).unwrap();
+        assert!(matches!(decoded, WireRequest::Unsupported(_)));
+    }
+
+    #[test]
+    fn decode_unsigned_requests() {
+        // A message from a version before requests were signed
+        #[derive(Serialize)]
+        enum OldRequest {
+            Message { from_app_uuid: String, to_app_uuid: String, message: Vec<u8> },
+        }
+        let encoded = cbor4ii::serde::to_vec(Vec::new(), &OldRequest::Message {
+            from_app_uuid: String::from("from"),
+            to_app_uuid: String::from("to"),
+            message: vec![1, 2, 3],
+        }).unwrap();
+        let decoded: WireRequest = cbor4ii::serde::from_slice(&encoded).unwrap();
+        assert!(matches!(decoded, WireRequest::Supported(Request::Message { signature: None, .. })));
+    }
+
+    #[test]
+    fn namespace_topics_by_app() {
+        let peer = PeerId::random();
+        let topic = app_topic(&peer, "1234", "rooms/general");
+        assert_eq!(topic.to_string(), format!("/mutiny/app/{peer}/1234/rooms/general"));
+        let peer_str = peer.to_base58();
+        assert_eq!(parse_app_topic(&topic.hash()), Some((peer_str.as_str(), "1234", "rooms/general")));
+        assert_ne!(app_topic(&peer, "1234", "general").hash(), app_topic(&peer, "5678", "general").hash());
+        assert_ne!(app_topic(&peer, "1234", "general").hash(), app_topic(&PeerId::random(), "1234", "general").hash());
+        assert_eq!(parse_app_topic(&gossipsub::IdentTopic::new("other").hash()), None);
+        assert_eq!(parse_app_topic(&gossipsub::IdentTopic::new("/mutiny/app/1234/general").hash()), None);
+    }
+
+    fn normalize(addr: &str) -> Option<String> {
+        normalize_address(addr.parse().unwrap()).map(|addr| addr.to_string())
+    }
+
+    #[test]
+    fn order_addresses_by_transport_preference() {
+        let addrs: Vec<Multiaddr> = [
+            "/ip4/192.168.1.2/tcp/4001",
+            "/ip4/192.168.1.2/udp/4001/quic-v1",
+            "/ip4/192.168.1.2/tcp/4002/ws",
+            "/ip4/192.168.1.3/tcp/4001",
+        ].iter().map(|addr| addr.parse().unwrap()).collect();
+        let names = |addrs: Vec<Multiaddr>| -> Vec<String> {
+            addrs.iter().map(|addr| addr.to_string()).collect()
+        };
+        assert_eq!(names(prefer_transports(addrs.clone(), &[Transport::Quic, Transport::Tcp])), vec![
+            "/ip4/192.168.1.2/udp/4001/quic-v1",
+            "/ip4/192.168.1.2/tcp/4001",
+            "/ip4/192.168.1.3/tcp/4001",
+        ]);
+        assert_eq!(names(prefer_transports(addrs, &[Transport::Tcp])), vec![
+            "/ip4/192.168.1.2/tcp/4001",
+            "/ip4/192.168.1.3/tcp/4001",
+        ]);
+    }
+
+    #[test]
+    fn normalize_ipv6_addresses() {
+        assert_eq!(normalize("/ip6/2001:db8::1/tcp/4001"), Some(String::from("/ip6/2001:db8::1/tcp/4001")));
+        assert_eq!(normalize("/ip6/::1/udp/4001/quic-v1"), Some(String::from("/ip6/::1/udp/4001/quic-v1")));
+        assert_eq!(normalize("/ip6/::ffff:192.168.1.2/tcp/4001"), Some(String::from("/ip4/192.168.1.2/tcp/4001")));
+        assert_eq!(normalize("/ip6/fe80::1/tcp/4001"), None);
+        assert_eq!(normalize("/ip6/::/tcp/4001"), None);
+        assert_eq!(normalize("/ip4/0.0.0.0/tcp/4001"), None);
+        assert_eq!(normalize("/ip4/192.168.1.2/tcp/4001"), Some(String::from("/ip4/192.168.1.2/tcp/4001")));
+        assert_eq!(normalize("/dns4/example.com/tcp/4001"), Some(String::from("/dns4/example.com/tcp/4001")));
+        let peer_id = PeerId::random();
+        assert_eq!(normalize(&format!("/ip4/192.168.1.2/tcp/4001/p2p/{peer_id}")), Some(String::from("/ip4/192.168.1.2/tcp/4001")));
+    }
+
+    #[test]
+    fn name_address_transports() {
+        let name = |addr: &str| Transport::of(&addr.parse().unwrap()).map(|t| t.to_string());
+        assert_eq!(name("/ip4/127.0.0.1/tcp/4001").as_deref(), Some("tcp"));
+        assert_eq!(name("/ip6/::1/udp/4001/quic-v1").as_deref(), Some("quic"));
+        assert_eq!(name("/ip4/127.0.0.1/tcp/4001/ws"), None);
+        assert_eq!(name("/ip4/127.0.0.1/udp/4001"), None);
+    }
+
+    #[test]
+    fn split_bootstrap_address() {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/192.168.1.2/tcp/4001/p2p/{peer_id}").parse().unwrap();
+        assert_eq!(split_peer_id(addr), Some((peer_id, "/ip4/192.168.1.2/tcp/4001".parse().unwrap())));
+        assert_eq!(split_peer_id("/ip4/192.168.1.2/tcp/4001".parse().unwrap()), None);
+    }
+}